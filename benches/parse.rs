@@ -0,0 +1,18 @@
+#[macro_use]
+extern crate criterion;
+extern crate rbot_parser;
+
+use criterion::Criterion;
+use rbot_parser::{parse_into, parse_message, Message};
+use std::hint::black_box;
+
+const LINE: &str = "PRIVMSG #channel :Hello there, this is a fairly ordinary chat line\r\n";
+
+fn bench_parse(c: &mut Criterion) {
+    c.bench_function("parse_message", |b| b.iter(|| parse_message(black_box(LINE)).unwrap()));
+    let mut msg = Message::default();
+    c.bench_function("parse_into", |b| b.iter(|| parse_into(&mut msg, black_box(LINE)).unwrap()));
+}
+
+criterion_group!(benches, bench_parse);
+criterion_main!(benches);
@@ -0,0 +1,39 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use rbot_parser::*;
+
+// Runs every accessor on `msg`. Kept in sync with `exercise_message` in the unit tests.
+fn exercise_message(msg: &Message<'_>) {
+    let _ = (msg.to_string(), msg.to_owned(), msg.to_bytes(), msg.command_str(), msg.to_whitespace_separated());
+    let _ = (msg.trailing(), msg.params_with_trailing(), msg.params_after(1), msg.numeric_args(), msg.is_error_numeric());
+    let _ = (msg.sender_nick(), msg.privmsg_target(), msg.privmsg_text(), msg.ctcp().as_ref().and_then(parse_dcc), msg.msgid());
+    let _ = (msg.join_channels(), msg.kick_info(), msg.reply_target(), msg.nick_change(), msg.is_echo("me"));
+    let _ = (msg.account_change(), msg.away_status(), msg.ping_token(), msg.pong_reply(), msg.quit_reason(), msg.part_info());
+    let _ = (msg.error_text(), msg.wallops_text(), msg.invite_info(), msg.topic_command(), msg.sasl_payload());
+    let _ = (msg.channels(), msg.channels_with_types(""), msg.raw_tag("a"), msg.tag("a"), msg.tag_value("a"), msg.has_tag("a"));
+    let _ = (msg.client_only_tags().count(), msg.server_tags().count(), msg.server_time_millis(), msg.batch(), msg.label());
+    let _ = (parse_isupport(msg), parse_who_reply(msg), parse_names_reply(msg), parse_topic(msg), parse_cap(msg), parse_batch(msg));
+    let _ = (parse_list_reply(msg), parse_metadata(msg), parse_mono(msg), parse_channel_mode_reply(msg));
+    let _ = parse_mode_params(&msg.params, "ovbk");
+    for param in &msg.params {
+        let _ = (strip_formatting(param), parse_prefix_token(param), is_valid_nick(param));
+    }
+}
+
+fuzz_target!(|data: &[u8]| {
+    if let Ok(msg) = parse_message_bytes(data) {
+        exercise_message(&msg);
+        // The fields are public, so the accessors mustn't rely on them being consistent
+        let mut msg = msg.clone();
+        msg.has_trailing = !msg.has_trailing;
+        exercise_message(&msg);
+    }
+    if let Ok(input) = std::str::from_utf8(data) {
+        let _ = parse_messages(input);
+        let _ = input.parse::<OwnedMessage>();
+    }
+    let mut stream = MessageStream::new();
+    stream.push(data);
+    while stream.next_message().is_some() {}
+});
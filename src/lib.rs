@@ -1,54 +1,406 @@
-#[macro_use]
+#![cfg_attr(not(feature = "std"), no_std)]
+
 extern crate nom;
+#[cfg(feature = "serde")]
+extern crate serde;
+#[cfg(not(feature = "std"))]
+#[macro_use]
+extern crate alloc;
+#[cfg(feature = "std")]
+extern crate core;
 
+#[cfg(not(feature = "std"))]
+use alloc::borrow::Cow;
+#[cfg(not(feature = "std"))]
+use alloc::string::{String, ToString};
+#[cfg(not(feature = "std"))]
+use alloc::vec::Vec;
+#[cfg(feature = "std")]
 use std::borrow::Cow;
-use std::str::from_utf8;
-use nom::space;
-use nom::IResult::*;
-use std::str::FromStr;
-use std::fmt;
+use core::str::{from_utf8, Utf8Error};
+use nom::{IResult, Needed};
+use nom::bytes::complete::{is_not, tag, take_while, take_while1};
+use nom::combinator::{map_opt, map_res, opt};
+use nom::sequence::{delimited, preceded, terminated, tuple};
+use core::str::FromStr;
+use core::fmt;
+use core::net::Ipv4Addr;
+use core::iter;
+
+// The error type of the parsers, recording which part of the message failed to parse
+#[derive(PartialEq, Debug)]
+struct Failure<'a> {
+    input: &'a [u8],
+    kind: FailureKind
+}
+#[derive(PartialEq, Debug)]
+enum FailureKind {
+    Nom(nom::error::ErrorKind),
+    InvalidUtf8 { valid_up_to: usize },
+    MissingCommand,
+    MalformedCommand,
+    MalformedPrefix
+}
+impl<'a> nom::error::ParseError<&'a [u8]> for Failure<'a> {
+    fn from_error_kind(input: &'a [u8], kind: nom::error::ErrorKind) -> Failure<'a> {
+        Failure { input, kind: FailureKind::Nom(kind) }
+    }
+    fn append(_: &'a [u8], _: nom::error::ErrorKind, other: Failure<'a>) -> Failure<'a> {
+        other
+    }
+}
+impl<'a> nom::error::FromExternalError<&'a [u8], Utf8Error> for Failure<'a> {
+    fn from_external_error(input: &'a [u8], _: nom::error::ErrorKind, e: Utf8Error) -> Failure<'a> {
+        Failure { input, kind: FailureKind::InvalidUtf8 { valid_up_to: e.valid_up_to() } }
+    }
+}
+type ParseResult<'a, O> = IResult<&'a [u8], O, Failure<'a>>;
 
-named!(nick_parser <&[u8], &str>, map_res!(chain!(nick: take_until!("!") ~ tag!("!"), ||{nick}), from_utf8));
-named!(user_parser <&[u8], &str>, map_res!(chain!(user: take_until!("@") ~ tag!("@"), ||{user}), from_utf8));
-named!(word_parser <&[u8], &str>, map_res!(take_until!(" "), from_utf8));
-named!(eol <&[u8], &str>, map_res!(take_until_and_consume!("\r"), from_utf8));
+fn word_parser(input: &[u8]) -> ParseResult<'_, &str> {
+    map_res(is_not(" \r\n"), from_utf8)(input)
+}
 
-#[derive(Debug)]
-pub struct ParserError {
-    data: String
+// Finds the end of the first line, accepting CRLF as well as a bare CR or LF. Returns the
+// length of the line without and with its terminator.
+fn find_line_end(input: &[u8]) -> Option<(usize, usize)> {
+    let end = input.iter().position(|&b| b == b'\r' || b == b'\n')?;
+    let terminator = if input[end..].starts_with(b"\r\n") { 2 } else { 1 };
+    Some((end, end + terminator))
+}
+fn eol(input: &[u8]) -> ParseResult<'_, &[u8]> {
+    match find_line_end(input) {
+        Some((end, next)) => Ok((&input[next..], &input[..end])),
+        None => Err(nom::Err::Incomplete(Needed::Unknown))
+    }
+}
+// Like eol, but a line that has already been split off its terminator ends with the input
+fn eol_or_end(input: &[u8]) -> ParseResult<'_, &[u8]> {
+    let (end, next) = find_line_end(input).unwrap_or((input.len(), input.len()));
+    Ok((&input[next..], &input[..end]))
+}
+
+// Only the space byte separates the parts of a message, as with the parameters
+fn spaces0(input: &[u8]) -> ParseResult<'_, &[u8]> {
+    take_while(|c| c == b' ')(input)
+}
+fn spaces1(input: &[u8]) -> ParseResult<'_, &[u8]> {
+    take_while1(|c| c == b' ')(input)
+}
+
+fn tags_parser(input: &[u8]) -> ParseResult<'_, &str> {
+    // The tags end at the line end too, so a line holding only tags fails on its missing command
+    // instead of running into the next line
+    delimited(tag("@"), map_res(is_not(" \r\n"), from_utf8), spaces0)(input)
+}
+fn split_tags_into<'a>(tags: &'a str, out: &mut Vec<(&'a str, Option<&'a str>)>) {
+    out.extend(tags.split(';')
+        .filter(|tag| !tag.is_empty())
+        .map(|tag| match tag.split_once('=') {
+            Some((key, value)) => (key, Some(value)),
+            None => (tag, None)
+        }));
+}
+
+/// Maximum length of a message in bytes, including the terminating CRLF (RFC 2812). IRCv3 tags
+/// don't count towards it.
+pub const MAX_LINE_LENGTH: usize = 512;
+/// Maximum length of the IRCv3 tags of a message in bytes, including the leading `@` and the
+/// space after them.
+pub const MAX_TAGS_LENGTH: usize = 8191;
+
+#[derive(PartialEq, Debug)]
+pub enum ParserError {
+    Incomplete,
+    InvalidUtf8 { position: usize },
+    MissingCommand { position: usize },
+    /// The command is neither three digits nor all ASCII letters
+    MalformedCommand { position: usize },
+    MalformedPrefix { position: usize },
+    /// The line without its tags is longer than `MAX_LINE_LENGTH`
+    LineTooLong { len: usize },
+    TagsTooLong { len: usize },
+    /// A parameter of a message being built can't be sent as is. Every parameter but the last
+    /// must be non-empty, mustn't start with `:` and mustn't contain a space, and none of them
+    /// may contain CR, LF or NUL.
+    InvalidParam { index: usize },
+    /// Input continues after the single line that was expected
+    TrailingInput { position: usize },
+    #[cfg(feature = "std")]
+    Io(std::io::ErrorKind),
+    Nom(String)
+}
+impl ParserError {
+    /// Returns the byte offset into the line where parsing failed, when there's one.
+    pub fn position(&self) -> Option<usize> {
+        match *self {
+            ParserError::InvalidUtf8 { position }
+            | ParserError::MissingCommand { position }
+            | ParserError::MalformedCommand { position }
+            | ParserError::MalformedPrefix { position }
+            | ParserError::TrailingInput { position } => Some(position),
+            _ => None
+        }
+    }
+
+    fn from_nom(input: &[u8], e: nom::Err<Failure>) -> ParserError {
+        let failure = match e {
+            nom::Err::Incomplete(_) => return ParserError::Incomplete,
+            nom::Err::Error(failure) | nom::Err::Failure(failure) => failure
+        };
+        let position = input.len() - failure.input.len();
+        match failure.kind {
+            FailureKind::Nom(kind) => ParserError::Nom(format!("{:?}", kind)),
+            FailureKind::InvalidUtf8 { valid_up_to } => ParserError::InvalidUtf8 { position: position + valid_up_to },
+            FailureKind::MissingCommand => ParserError::MissingCommand { position },
+            FailureKind::MalformedCommand => ParserError::MalformedCommand { position },
+            FailureKind::MalformedPrefix => ParserError::MalformedPrefix { position }
+        }
+    }
+}
+impl fmt::Display for ParserError {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            ParserError::Incomplete => write!(fmt, "Incomplete input"),
+            ParserError::InvalidUtf8 { position } => write!(fmt, "Invalid UTF-8 at byte {}", position),
+            ParserError::MissingCommand { position } => write!(fmt, "Missing command at byte {}", position),
+            ParserError::MalformedCommand { position } => write!(fmt, "Malformed command at byte {}", position),
+            ParserError::MalformedPrefix { position } => write!(fmt, "Malformed prefix at byte {}", position),
+            ParserError::LineTooLong { len } => write!(fmt, "Line is {} bytes long, the maximum is {}", len, MAX_LINE_LENGTH),
+            ParserError::TagsTooLong { len } => write!(fmt, "Tags are {} bytes long, the maximum is {}", len, MAX_TAGS_LENGTH),
+            ParserError::InvalidParam { index } => write!(fmt, "Parameter {} can't be sent", index),
+            ParserError::TrailingInput { position } => write!(fmt, "Unexpected input after the line at byte {}", position),
+            #[cfg(feature = "std")]
+            ParserError::Io(kind) => write!(fmt, "I/O error: {}", kind),
+            ParserError::Nom(ref e) => write!(fmt, "Error: {}", e)
+        }
+    }
 }
-impl std::fmt::Display for ParserError {
-    fn fmt(&self, fmt: &mut std::fmt::Formatter) -> std::fmt::Result {
-        write!(fmt, "{}", self.data)
+#[cfg(feature = "std")]
+impl std::error::Error for ParserError {}
+
+/// Removes mIRC formatting from message text: bold, italics, underline, strikethrough,
+/// monospace, reverse and reset codes, as well as colors given as `\x03fg,bg` with one or two
+/// digits each or as `\x04RRGGBB,RRGGBB` in hex. Borrows the input when there's nothing to remove.
+pub fn strip_formatting(text: &str) -> Cow<'_, str> {
+    const CODES: &[u8] = b"\x02\x03\x04\x0f\x11\x16\x1d\x1e\x1f";
+    let bytes = text.as_bytes();
+    let mut next = match bytes.iter().position(|b| CODES.contains(b)) {
+        Some(next) => next,
+        None => return Cow::Borrowed(text)
+    };
+    let mut out = String::with_capacity(text.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        out.push_str(&text[i..next]);
+        if next == bytes.len() {
+            break;
+        }
+        let code = bytes[next];
+        i = next + 1;
+        // Returns the length of the color at `from`, accounting for the digits it's allowed
+        let color_len = |from: usize| {
+            let digits = |is_digit: fn(&u8) -> bool, max| bytes[from..].iter().take(max).take_while(|b| is_digit(b)).count();
+            match code {
+                b'\x03' => digits(u8::is_ascii_digit, 2),
+                b'\x04' => match digits(u8::is_ascii_hexdigit, 6) { 6 => 6, _ => 0 },
+                _ => 0
+            }
+        };
+        let foreground = color_len(i);
+        i += foreground;
+        if foreground > 0 && bytes.get(i) == Some(&b',') {
+            let background = color_len(i + 1);
+            if background > 0 {
+                i += 1 + background;
+            }
+        }
+        next = bytes[i..].iter().position(|b| CODES.contains(b)).map_or(bytes.len(), |p| i + p);
     }
+    Cow::Owned(out)
+}
+
+/// How a server compares nicks and channel names, as given by the ISUPPORT `CASEMAPPING` token.
+#[derive(PartialEq, Eq, Debug, Clone, Copy, Default)]
+pub enum CaseMapping {
+    /// `ascii`, where only `A-Z` have lowercase forms
+    Ascii,
+    /// `rfc1459`, where `[]\\~` are also the uppercase of `{}|^`. Used when the server doesn't say.
+    #[default]
+    Rfc1459,
+    /// `strict-rfc1459`, like `rfc1459` without `~` and `^`
+    Strict
 }
-impl std::error::Error for ParserError {
-    fn description(&self) -> &str {
-        &self.data
+impl CaseMapping {
+    /// Looks up the casemapping named by the value of a `CASEMAPPING` token.
+    pub fn from_token(value: &str) -> Option<CaseMapping> {
+        match value {
+            "ascii" => Some(CaseMapping::Ascii),
+            "rfc1459" => Some(CaseMapping::Rfc1459),
+            "strict-rfc1459" => Some(CaseMapping::Strict),
+            _ => None
+        }
+    }
+    fn to_lower(self, b: u8) -> u8 {
+        match (self, b) {
+            (CaseMapping::Ascii, _) => b.to_ascii_lowercase(),
+            (_, b'[') => b'{',
+            (_, b']') => b'}',
+            (_, b'\\') => b'|',
+            (CaseMapping::Rfc1459, b'~') => b'^',
+            _ => b.to_ascii_lowercase()
+        }
     }
 }
-impl<'a> From<nom::Err<&'a [u8]>> for ParserError {
-    fn from(e: nom::Err<&'a [u8]>) -> ParserError {
-        ParserError {
-            data: format!("Error: {:?}", e)
+
+/// Compares nicks or channel names case-insensitively under `mapping`. Bytes outside ASCII
+/// are compared as is.
+pub fn irc_eq(a: &str, b: &str, mapping: CaseMapping) -> bool {
+    a.len() == b.len() && a.bytes().zip(b.bytes()).all(|(a, b)| mapping.to_lower(a) == mapping.to_lower(b))
+}
+
+/// Whether a tag key names an IRCv3 client-only tag, e.g. `+example.com/reply`. The `+` is
+/// part of the key.
+pub fn is_client_only_tag(key: &str) -> bool {
+    key.starts_with('+')
+}
+
+// Parses an ISO 8601 UTC timestamp with an optional fraction of a second into unix milliseconds
+fn parse_server_time(time: &str) -> Option<i64> {
+    fn number(digits: &str, len: usize) -> Option<i64> {
+        if digits.len() != len || !digits.bytes().all(|b| b.is_ascii_digit()) {
+            return None;
         }
+        digits.parse().ok()
     }
+    let time = time.strip_suffix('Z')?;
+    let (time, millis) = match time.split_once('.') {
+        Some((time, fraction)) => {
+            if fraction.is_empty() || fraction.len() > 9 || !fraction.bytes().all(|b| b.is_ascii_digit()) {
+                return None;
+            }
+            // Only millisecond precision is kept, so "5" is 500 ms and "123456" is 123 ms
+            let millis = fraction.bytes().chain(iter::repeat(b'0')).take(3).fold(0, |acc, b| acc * 10 + i64::from(b - b'0'));
+            (time, millis)
+        },
+        None => (time, 0)
+    };
+    let (date, clock) = time.split_once('T')?;
+    let mut date = date.split('-');
+    let (year, month, day) = (number(date.next()?, 4)?, number(date.next()?, 2)?, number(date.next()?, 2)?);
+    let mut clock = clock.split(':');
+    let (hour, minute, second) = (number(clock.next()?, 2)?, number(clock.next()?, 2)?, number(clock.next()?, 2)?);
+    if date.next().is_some() || clock.next().is_some() {
+        return None;
+    }
+    let leap = year % 4 == 0 && (year % 100 != 0 || year % 400 == 0);
+    let month_days = [31, if leap { 29 } else { 28 }, 31, 30, 31, 30, 31, 31, 30, 31, 30, 31];
+    if !(1..=12).contains(&month) || day < 1 || day > month_days[month as usize - 1] || hour > 23 || minute > 59 || second > 59 {
+        return None;
+    }
+    // Days since the epoch from the civil date, counting years from March so leap days come last
+    let y = if month <= 2 { year - 1 } else { year };
+    let year_of_era = y.rem_euclid(400);
+    let day_of_year = (153 * ((month + 9) % 12) + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    let days = y.div_euclid(400) * 146_097 + day_of_era - 719_468;
+    Some(((days * 24 + hour) * 60 + minute) * 60_000 + second * 1000 + millis)
 }
 
-#[derive(PartialEq, Debug)]
+/// Unescapes an IRCv3 tag value, borrowing the input when it contains no escapes.
+pub fn unescape_tag_value(raw: &str) -> Cow<'_, str> {
+    if !raw.contains('\\') {
+        return Cow::Borrowed(raw);
+    }
+    let mut ret = String::with_capacity(raw.len());
+    let mut chars = raw.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            ret.push(c);
+            continue;
+        }
+        // A lone backslash at the end of the value is dropped
+        match chars.next() {
+            Some(':') => ret.push(';'),
+            Some('s') => ret.push(' '),
+            Some('r') => ret.push('\r'),
+            Some('n') => ret.push('\n'),
+            Some(other) => ret.push(other),
+            None => {}
+        }
+    }
+    Cow::Owned(ret)
+}
+
+#[derive(PartialEq, Eq, Debug, Clone)]
 pub enum Prefix<'a> {
-    User(&'a str, &'a str, &'a str),
+    User { nick: &'a str, user: Option<&'a str>, host: Option<&'a str> },
     Server(&'a str)
 }
 impl<'a> fmt::Display for Prefix<'a> {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         match *self {
-            Prefix::User(nick, user, host) => write!(f, "{}!{}@{}", nick, user, host),
+            Prefix::User { nick, user, host } => {
+                write!(f, "{}", nick)?;
+                if let Some(user) = user {
+                    write!(f, "!{}", user)?;
+                }
+                if let Some(host) = host {
+                    write!(f, "@{}", host)?;
+                }
+                Ok(())
+            },
             Prefix::Server(serverstr) => write!(f, "{}", serverstr)
         }
     }
 }
-#[derive(PartialEq, Debug)]
+impl<'a> Prefix<'a> {
+    pub fn is_user(&self) -> bool {
+        matches!(*self, Prefix::User { .. })
+    }
+    pub fn is_server(&self) -> bool {
+        matches!(*self, Prefix::Server(_))
+    }
+    /// Splits a server name into its dotted labels, e.g. for matching it against `*.example.org`.
+    /// Returns `None` for user prefixes and for names with empty labels.
+    pub fn server_labels(&self) -> Option<Vec<&'a str>> {
+        match *self {
+            Prefix::Server(server) => {
+                let labels: Vec<&str> = server.split('.').collect();
+                if labels.iter().any(|label| label.is_empty()) { None } else { Some(labels) }
+            },
+            Prefix::User { .. } => None
+        }
+    }
+    pub fn nick(&self) -> Option<&'a str> {
+        match *self {
+            Prefix::User { nick, .. } => Some(nick),
+            Prefix::Server(_) => None
+        }
+    }
+    pub fn user(&self) -> Option<&'a str> {
+        match *self {
+            Prefix::User { user, .. } => user,
+            Prefix::Server(_) => None
+        }
+    }
+    pub fn host(&self) -> Option<&'a str> {
+        match *self {
+            Prefix::User { host, .. } => host,
+            Prefix::Server(_) => None
+        }
+    }
+    pub fn to_owned(&self) -> OwnedPrefix {
+        match *self {
+            Prefix::User { nick, user, host } => OwnedPrefix::User {
+                nick: nick.to_string(),
+                user: user.map(|u| u.to_string()),
+                host: host.map(|h| h.to_string())
+            },
+            Prefix::Server(serverstr) => OwnedPrefix::Server(serverstr.to_string())
+        }
+    }
+}
+#[derive(PartialEq, Eq, Debug, Clone)]
 pub enum Command<'a> {
     Named(Cow<'a, str>),
     Numeric(u16)
@@ -57,167 +409,3233 @@ impl<'a> fmt::Display for Command<'a> {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         match *self {
             Command::Named(ref s) => write!(f, "{}", s),
-            Command::Numeric(n) => write!(f, "{}", n)
+            Command::Numeric(n) => write!(f, "{:03}", n)
         }
     }
 }
 
-#[derive(Debug)]
+/// Compares a named command case-insensitively, like `Command::matches`.
+impl<'a, 'b> PartialEq<&'b str> for Command<'a> {
+    fn eq(&self, other: &&'b str) -> bool {
+        self.matches(other)
+    }
+}
+impl<'a> PartialEq<u16> for Command<'a> {
+    fn eq(&self, other: &u16) -> bool {
+        self.as_numeric() == Some(*other)
+    }
+}
+
+impl<'a> Command<'a> {
+    /// Creates a numeric command, rejecting values that don't fit in three digits.
+    pub fn numeric(n: u16) -> Option<Command<'a>> {
+        if n <= 999 { Some(Command::Numeric(n)) } else { None }
+    }
+    pub fn is_numeric(&self) -> bool {
+        self.as_numeric().is_some()
+    }
+    /// Whether this is an error numeric, 400 to 599 inclusive.
+    pub fn is_error(&self) -> bool {
+        self.as_numeric().is_some_and(|n| (400..=599).contains(&n))
+    }
+    /// Whether this is a numeric reply other than an error, 001 to 399 inclusive.
+    pub fn is_reply(&self) -> bool {
+        self.as_numeric().is_some_and(|n| (1..=399).contains(&n))
+    }
+    pub fn as_numeric(&self) -> Option<u16> {
+        match *self {
+            Command::Numeric(n) => Some(n),
+            Command::Named(_) => None
+        }
+    }
+    pub fn as_named(&self) -> Option<&str> {
+        match *self {
+            Command::Named(ref cmd) => Some(cmd),
+            Command::Numeric(_) => None
+        }
+    }
+    /// Compares a named command case-insensitively, as command names are on the wire.
+    pub fn matches(&self, name: &str) -> bool {
+        match *self {
+            Command::Named(ref cmd) => cmd.eq_ignore_ascii_case(name),
+            Command::Numeric(_) => false
+        }
+    }
+    /// Like `matches`, but a numeric also matches its three-digit number as on the wire and its
+    /// reply name, e.g. `"001"` and `"RPL_WELCOME"` both match 001.
+    pub fn matches_name(&self, name: &str) -> bool {
+        match *self {
+            Command::Named(_) => self.matches(name),
+            Command::Numeric(n) if name.len() == 3 && name.bytes().all(|b| b.is_ascii_digit()) => name.parse() == Ok(n),
+            Command::Numeric(_) => self.numeric_name().is_some_and(|known| known.eq_ignore_ascii_case(name))
+        }
+    }
+    /// Returns the RFC 1459/2812 name of a known numeric reply, such as `RPL_WELCOME` for 001.
+    pub fn numeric_name(&self) -> Option<&'static str> {
+        match *self {
+            Command::Numeric(1) => Some("RPL_WELCOME"),
+            Command::Numeric(2) => Some("RPL_YOURHOST"),
+            Command::Numeric(3) => Some("RPL_CREATED"),
+            Command::Numeric(4) => Some("RPL_MYINFO"),
+            Command::Numeric(5) => Some("RPL_ISUPPORT"),
+            Command::Numeric(221) => Some("RPL_UMODEIS"),
+            Command::Numeric(251) => Some("RPL_LUSERCLIENT"),
+            Command::Numeric(252) => Some("RPL_LUSEROP"),
+            Command::Numeric(253) => Some("RPL_LUSERUNKNOWN"),
+            Command::Numeric(254) => Some("RPL_LUSERCHANNELS"),
+            Command::Numeric(255) => Some("RPL_LUSERME"),
+            Command::Numeric(301) => Some("RPL_AWAY"),
+            Command::Numeric(305) => Some("RPL_UNAWAY"),
+            Command::Numeric(306) => Some("RPL_NOWAWAY"),
+            Command::Numeric(311) => Some("RPL_WHOISUSER"),
+            Command::Numeric(312) => Some("RPL_WHOISSERVER"),
+            Command::Numeric(313) => Some("RPL_WHOISOPERATOR"),
+            Command::Numeric(315) => Some("RPL_ENDOFWHO"),
+            Command::Numeric(317) => Some("RPL_WHOISIDLE"),
+            Command::Numeric(318) => Some("RPL_ENDOFWHOIS"),
+            Command::Numeric(319) => Some("RPL_WHOISCHANNELS"),
+            Command::Numeric(321) => Some("RPL_LISTSTART"),
+            Command::Numeric(322) => Some("RPL_LIST"),
+            Command::Numeric(323) => Some("RPL_LISTEND"),
+            Command::Numeric(324) => Some("RPL_CHANNELMODEIS"),
+            Command::Numeric(331) => Some("RPL_NOTOPIC"),
+            Command::Numeric(332) => Some("RPL_TOPIC"),
+            Command::Numeric(333) => Some("RPL_TOPICWHOTIME"),
+            Command::Numeric(341) => Some("RPL_INVITING"),
+            Command::Numeric(352) => Some("RPL_WHOREPLY"),
+            Command::Numeric(353) => Some("RPL_NAMREPLY"),
+            Command::Numeric(366) => Some("RPL_ENDOFNAMES"),
+            Command::Numeric(367) => Some("RPL_BANLIST"),
+            Command::Numeric(368) => Some("RPL_ENDOFBANLIST"),
+            Command::Numeric(372) => Some("RPL_MOTD"),
+            Command::Numeric(375) => Some("RPL_MOTDSTART"),
+            Command::Numeric(376) => Some("RPL_ENDOFMOTD"),
+            Command::Numeric(381) => Some("RPL_YOUREOPER"),
+            Command::Numeric(401) => Some("ERR_NOSUCHNICK"),
+            Command::Numeric(402) => Some("ERR_NOSUCHSERVER"),
+            Command::Numeric(403) => Some("ERR_NOSUCHCHANNEL"),
+            Command::Numeric(404) => Some("ERR_CANNOTSENDTOCHAN"),
+            Command::Numeric(405) => Some("ERR_TOOMANYCHANNELS"),
+            Command::Numeric(411) => Some("ERR_NORECIPIENT"),
+            Command::Numeric(412) => Some("ERR_NOTEXTTOSEND"),
+            Command::Numeric(421) => Some("ERR_UNKNOWNCOMMAND"),
+            Command::Numeric(422) => Some("ERR_NOMOTD"),
+            Command::Numeric(431) => Some("ERR_NONICKNAMEGIVEN"),
+            Command::Numeric(432) => Some("ERR_ERRONEUSNICKNAME"),
+            Command::Numeric(433) => Some("ERR_NICKNAMEINUSE"),
+            Command::Numeric(436) => Some("ERR_NICKCOLLISION"),
+            Command::Numeric(441) => Some("ERR_USERNOTINCHANNEL"),
+            Command::Numeric(442) => Some("ERR_NOTONCHANNEL"),
+            Command::Numeric(443) => Some("ERR_USERONCHANNEL"),
+            Command::Numeric(451) => Some("ERR_NOTREGISTERED"),
+            Command::Numeric(461) => Some("ERR_NEEDMOREPARAMS"),
+            Command::Numeric(462) => Some("ERR_ALREADYREGISTRED"),
+            Command::Numeric(464) => Some("ERR_PASSWDMISMATCH"),
+            Command::Numeric(465) => Some("ERR_YOUREBANNEDCREEP"),
+            Command::Numeric(471) => Some("ERR_CHANNELISFULL"),
+            Command::Numeric(472) => Some("ERR_UNKNOWNMODE"),
+            Command::Numeric(473) => Some("ERR_INVITEONLYCHAN"),
+            Command::Numeric(474) => Some("ERR_BANNEDFROMCHAN"),
+            Command::Numeric(475) => Some("ERR_BADCHANNELKEY"),
+            Command::Numeric(481) => Some("ERR_NOPRIVILEGES"),
+            Command::Numeric(482) => Some("ERR_CHANOPRIVSNEEDED"),
+            Command::Numeric(491) => Some("ERR_NOOPERHOST"),
+            Command::Numeric(501) => Some("ERR_UMODEUNKNOWNFLAG"),
+            Command::Numeric(502) => Some("ERR_USERSDONTMATCH"),
+            _ => None
+        }
+    }
+    pub fn to_owned(&self) -> OwnedCommand {
+        match *self {
+            Command::Named(ref s) => OwnedCommand::Named(s.to_string()),
+            Command::Numeric(n) => OwnedCommand::Numeric(n)
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
 pub struct Message<'a> {
+    pub tags: Vec<(&'a str, Option<&'a str>)>,
     pub prefix: Option<Prefix<'a>>,
     pub command: Command<'a>,
-    pub params: Vec<&'a str>
+    pub params: Vec<Cow<'a, str>>,
+    /// Whether the last of `params` was given as a colon-prefixed trailing parameter
+    pub has_trailing: bool,
+    /// The line as it was received, without its terminator. Borrowed from the input unless it
+    /// had to be decoded lossily, and empty for messages that weren't parsed.
+    pub raw: Cow<'a, str>,
+    /// The bytes of `raw` before decoding, which differ from it for lines that weren't valid UTF-8
+    pub raw_bytes: &'a [u8]
 }
 
-impl<'a> fmt::Display for Message<'a> {
-    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        // TODO: I don't think this ret.push_str() stuff is ideal
-        let mut ret = match self.prefix {
-            Some(ref prefix) => format!(":{} ", prefix),
-            None => "".to_string()
-        };
-        ret.push_str(format!("{} ", self.command).as_ref());
-        for param in self.params.iter() {
-            // TODO: The output format of this is not 1:1 to the string that was parsed
-            ret.push_str(format!("{} ", param).as_ref());
-        }
-        write!(f, "{}", ret)
-    }
-}
-
-named!(message_parser <&[u8], Message>,
-    chain!(
-        parsed_prefix: prefix_parser? ~
-        parsed_command: command_parser ~
-        parsed_params: map_res!(take_until_and_consume!(":"), from_utf8)? ~
-        parsed_trailing: eol,
-        || {
-            let params = match parsed_params {
-                Some(p) => {
-                    let _: &str = p; // TODO: This looks stupid. How should this be done?
-                    p.split_whitespace()
-                        .chain(std::iter::repeat(parsed_trailing).take(1))
-                        .collect()
-                },
-                None => parsed_trailing.split_whitespace().collect()
-            };
-            Message {
-                prefix: parsed_prefix,
-                command: parsed_command,
-                params: params
-            }
-        }
-    )
-);
-
-named!(command_parser <&[u8], Command>,
-    chain!(
-        cmd: word_parser,
-        || {
-            match FromStr::from_str(cmd) {
-                Ok(numericcmd) => Command::Numeric(numericcmd),
-                Err(_) => Command::Named(cmd.into())
-            }
-        }
-    )
-);
-
-named!(prefix_parser <&[u8], Prefix>,
-    chain!(
-        tag!(":") ~
-        prefix: word_parser ~
-        space,
-        || {
-            match host_parser(prefix.as_bytes()) {
-                Done(_, (nick, user, host)) => Prefix::User(nick, user, host),
-                _ => Prefix::Server(prefix)
-            }
-        }
-    )
-);
-named!(host_parser <&[u8], (&str, &str, &str)>,
-    chain!(
-       nick: nick_parser ~
-       user: user_parser ~
-       host: word_parser ,
-       ||{(nick, user, host)}
-    )
-);
-
-pub fn parse_message(input: &str) -> Result<Message, ParserError> {
-    match message_parser(input.as_bytes()) {
-        Done(_, msg) => Ok(msg),
-        Incomplete(i) => Err(ParserError {
-            data: format!("Incomplete: {:?}", i)
-        }),
-        Error(e) => Err(From::from(e))
+/// Compares messages structurally. `raw` is left out, so a parsed message equals the same
+/// message built by other means or received with different spacing.
+impl<'a, 'b> PartialEq<Message<'b>> for Message<'a> {
+    fn eq(&self, other: &Message<'b>) -> bool {
+        self.tags == other.tags
+            && self.prefix == other.prefix
+            && self.command == other.command
+            && self.params == other.params
+            && self.has_trailing == other.has_trailing
     }
 }
+impl<'a> Eq for Message<'a> {}
 
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use nom::IResult::*;
-    #[test]
-    fn test_parsing_host() {
-        match super::host_parser(b"user!host@example.com ") {
-            Done(_, (nick, user, host)) => {
-                assert_eq!(nick, "user");
-                assert_eq!(user, "host");
-                assert_eq!(host, "example.com");
-            },
-            Incomplete(i) => panic!(format!("Incomplete: {:?}", i)),
-            _ => panic!("Error while parsing host")
+/// An empty message to parse into with `parse_into`.
+impl<'a> Default for Message<'a> {
+    fn default() -> Message<'a> {
+        Message {
+            tags: Vec::new(),
+            prefix: None,
+            command: Command::Named(Cow::Borrowed("")),
+            params: Vec::new(),
+            has_trailing: false,
+            raw: Cow::Borrowed(""),
+            raw_bytes: b""
         }
     }
-    #[test]
-    fn test_parsing_line() {
-        match super::message_parser(b"NOTICE AUTH :*** Looking up your hostname\r") {
-            Done(_, msg) => {
-                assert_eq!(msg.prefix, None);
-                assert_eq!(msg.command, Command::Named("NOTICE".into()));
-                assert_eq!(msg.params, vec!["AUTH", "*** Looking up your hostname"]);
-            },
-            Incomplete(i) => panic!(format!("Incomplete: {:?}", i)),
-            _ => panic!("Error while parsing auth message")
+}
+
+impl<'a> Message<'a> {
+    /// Creates a message with a named command and no params yet, borrowing everything.
+    ///
+    /// These constructors fail with `ParserError::MalformedCommand` if the command isn't all
+    /// ASCII letters, and with `ParserError::InvalidParam` on params `MessageBuilder::build`
+    /// would reject, so that nothing but the intended line ends up on the wire.
+    pub fn new(command: &'a str) -> Result<Message<'a>, ParserError> {
+        Message::with_params(command, &[], false)
+    }
+    pub fn privmsg(target: &'a str, text: &'a str) -> Result<Message<'a>, ParserError> {
+        Message::with_params("PRIVMSG", &[target, text], true)
+    }
+    pub fn notice(target: &'a str, text: &'a str) -> Result<Message<'a>, ParserError> {
+        Message::with_params("NOTICE", &[target, text], true)
+    }
+    pub fn join(channel: &'a str) -> Result<Message<'a>, ParserError> {
+        Message::with_params("JOIN", &[channel], false)
+    }
+    pub fn nick(name: &'a str) -> Result<Message<'a>, ParserError> {
+        Message::with_params("NICK", &[name], false)
+    }
+    /// Creates the USER message of registration, with the unused mode and server params set to
+    /// `0` and `*` as in RFC 2812.
+    pub fn user(user: &'a str, realname: &'a str) -> Result<Message<'a>, ParserError> {
+        Message::with_params("USER", &[user, "0", "*", realname], true)
+    }
+    fn with_params(command: &'a str, params: &[&'a str], has_trailing: bool) -> Result<Message<'a>, ParserError> {
+        check_command(command)?;
+        check_params(params)?;
+        Ok(Message {
+            command: Command::Named(Cow::Borrowed(command)),
+            params: params.iter().map(|&param| Cow::Borrowed(param)).collect(),
+            has_trailing,
+            ..Message::default()
+        })
+    }
+    pub fn to_owned(&self) -> OwnedMessage {
+        OwnedMessage {
+            tags: self.tags.iter()
+                .map(|&(key, value)| (key.to_string(), value.map(|v| v.to_string())))
+                .collect(),
+            prefix: self.prefix.as_ref().map(|p| p.to_owned()),
+            command: self.command.to_owned(),
+            params: self.params.iter().map(|p| p.to_string()).collect(),
+            has_trailing: self.has_trailing
         }
     }
-    #[test]
-    fn test_parsing_line_without_trailing() {
-        match super::message_parser(b":port80a.se.quakenet.org 004 RustBot port80a.se.quakenet.org u2.10.12.10+snircd(1.3.4a) dioswkgxRXInP biklmnopstvrDcCNuMT bklov\r\n") {
-            Done(_, msg) => {
-                assert_eq!(msg.prefix, Some(Prefix::Server("port80a.se.quakenet.org")));
-                assert_eq!(msg.command, Command::Numeric(4));
-                assert_eq!(msg.params, vec!["RustBot", "port80a.se.quakenet.org", "u2.10.12.10+snircd(1.3.4a)", "dioswkgxRXInP", "biklmnopstvrDcCNuMT", "bklov"]);
+    /// Returns the command as a string for dispatching over named and numeric commands alike.
+    /// Numerics are zero-padded to three digits as on the wire, e.g. `"004"`, never `"4"`.
+    pub fn command_str(&self) -> Cow<'_, str> {
+        match self.command {
+            Command::Named(ref cmd) => Cow::Borrowed(cmd),
+            Command::Numeric(_) => Cow::Owned(self.command.to_string())
+        }
+    }
+    /// Serializes the message for sending, the same as `Display`. The trailing parameter gets its
+    /// colon whenever it needs one and the line ends in CRLF.
+    pub fn to_wire_string(&self) -> String {
+        self.to_wire_string_with("\r\n")
+    }
+    /// Serializes the message like `to_wire_string` but ends it with `terminator`, e.g. `"\n"`
+    /// for logs.
+    pub fn to_wire_string_with(&self, terminator: &str) -> String {
+        let mut out = String::new();
+        // Writing into a String can't fail
+        let _ = self.write_wire(&mut out, terminator);
+        out
+    }
+    /// Serializes the message for sending like `to_wire_string`, as bytes to write to a socket.
+    /// A parsed message serializes back to the bytes it was parsed from, as long as they used
+    /// single spaces and CRLF. Invalid UTF-8 is kept as received unless the message was changed.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut out = Vec::new();
+        match self.undecoded_line() {
+            Some(line) => {
+                out.extend_from_slice(line);
+                out.extend_from_slice(b"\r\n");
             },
-            Incomplete(i) => panic!(format!("Incomplete: {:?}", i)),
-            _ => panic!("Error while parsing a message without trailing stuff")
+            // Writing into a Vec can't fail
+            None => { let _ = self.write_wire(&mut ByteWriter(&mut out), "\r\n"); }
         }
+        out
     }
-    #[test]
-    fn test_parsing_prefix() {
-        match super::prefix_parser(b":this.represents.a.server.prefix ") {
-            Done(left, Prefix::Server(server)) => {
-                assert_eq!(server, "this.represents.a.server.prefix");
-                assert_eq!(left.len(), 0);
+    /// Writes the message to `w` the same as `to_bytes`, without building it in memory first.
+    #[cfg(feature = "std")]
+    pub fn write_to<W: std::io::Write>(&self, w: &mut W) -> std::io::Result<()> {
+        match self.undecoded_line() {
+            Some(line) => {
+                w.write_all(line)?;
+                w.write_all(b"\r\n")
             },
-            Incomplete(i) => panic!(format!("Incomplete: {:?}", i)),
-            _ => panic!("Error while parsing prefix")
+            None => write!(w, "{}", self)
         }
     }
-    #[test]
-    fn test_parsing_message_using_parse_message() {
-        let msg = "NOTICE AUTH :*** Looking up your hostname\r\nNOTICE AUTH :*** Checking Ident\r\nNOTICE AUTH :*** Found your hostname\r\n";
-        for m in msg.split("\n") {
-            if m.len() <= 1 { continue; } // TODO: Better way to split?
-            match parse_message(m) {
-                Ok(_) => {},
-                Err(_) => panic!("Could not parse line {:?} from message.", m)
-            };
+    // Returns the received bytes of a line that was decoded lossily, as long as the message
+    // still serializes to what they decoded to
+    fn undecoded_line(&self) -> Option<&'a [u8]> {
+        match self.raw {
+            Cow::Owned(ref raw) if !self.raw_bytes.is_empty() && self.to_wire_string_with("") == *raw => Some(self.raw_bytes),
+            _ => None
         }
     }
-    #[test]
-    fn test_inline_host() {
-        parse_message(":server.example.com 333 RustBot #channel user!host@example.com 123456789\r\n").unwrap();
+    /// Returns the colon-prefixed trailing parameter, if the message had one.
+    pub fn trailing(&self) -> Option<&str> {
+        if self.has_trailing { self.params.last().map(|trailing| trailing.as_ref()) } else { None }
+    }
+    /// Returns all of the params with the trailing one, if any, as the last of them. This is the
+    /// legacy-compatible view `params` gives today, for code that should keep working if the
+    /// trailing param is ever stored apart from the middle ones.
+    pub fn params_with_trailing(&self) -> Vec<&str> {
+        self.params.iter().map(|param| param.as_ref()).collect()
+    }
+    /// Returns the params after the first `n`, or nothing if there are no more than `n`.
+    pub fn params_after(&self, n: usize) -> &[Cow<'a, str>] {
+        self.params.get(n..).unwrap_or(&[])
+    }
+    /// Whether the server rejected something with an error numeric, see `Command::is_error`.
+    pub fn is_error_numeric(&self) -> bool {
+        self.command.is_error()
+    }
+    /// Returns the params of a numeric reply without the leading nick of the client it was
+    /// sent to. A numeric without any params gives an empty slice.
+    pub fn numeric_args(&self) -> Option<&[Cow<'a, str>]> {
+        if !self.command.is_numeric() {
+            return None;
+        }
+        Some(self.params_after(1))
+    }
+    /// Whether the message has a server prefix. Messages without a prefix come from the
+    /// server we're connected to, but that can't be told apart from a missing prefix here.
+    pub fn is_from_server(&self) -> bool {
+        self.prefix.as_ref().is_some_and(Prefix::is_server)
+    }
+    /// Compares only the command, case-insensitively, and the parameters, e.g. to recognize our
+    /// own message echoed back by the server with a prefix and tags added.
+    pub fn eq_ignore_prefix(&self, other: &Message<'_>) -> bool {
+        let same_command = match other.command {
+            Command::Named(ref name) => self.command.matches(name),
+            Command::Numeric(_) => self.command == other.command
+        };
+        same_command && self.params == other.params
+    }
+    /// Returns the nick of the user or the name of the server that sent the message.
+    pub fn sender_nick(&self) -> Option<&'a str> {
+        match self.prefix {
+            Some(Prefix::User { nick, .. }) => Some(nick),
+            Some(Prefix::Server(serverstr)) => Some(serverstr),
+            None => None
+        }
+    }
+    /// Renders the message for humans in wire order, `prefix command params`, with single
+    /// spaces and without the colons, tags or line terminator.
+    pub fn to_whitespace_separated(&self) -> String {
+        let mut out = String::new();
+        if let Some(ref prefix) = self.prefix {
+            out.push_str(&prefix.to_string());
+            out.push(' ');
+        }
+        out.push_str(&self.command.to_string());
+        for param in &self.params {
+            out.push(' ');
+            out.push_str(param);
+        }
+        out
+    }
+    /// Whether this is a PRIVMSG, NOTICE or TAGMSG we sent as `my_nick`, echoed back by a server
+    /// with the IRCv3 `echo-message` capability. Nicks are compared under RFC 1459 casemapping.
+    pub fn is_echo(&self, my_nick: &str) -> bool {
+        let echoed = ["PRIVMSG", "NOTICE", "TAGMSG"].iter().any(|&cmd| self.command.matches(cmd));
+        match self.prefix {
+            Some(Prefix::User { nick, .. }) => echoed && irc_eq(nick, my_nick, CaseMapping::Rfc1459),
+            _ => false
+        }
+    }
+    /// Returns the channel or nick a PRIVMSG was sent to.
+    pub fn privmsg_target(&self) -> Option<&str> {
+        self.privmsg_params().map(|(target, _)| target)
+    }
+    /// Returns the text of a PRIVMSG.
+    pub fn privmsg_text(&self) -> Option<&str> {
+        self.privmsg_params().map(|(_, text)| text)
+    }
+    fn privmsg_params(&self) -> Option<(&str, &str)> {
+        match self.params[..] {
+            [ref target, ref text] if self.command.matches("PRIVMSG") => Some((target, text)),
+            _ => None
+        }
+    }
+    /// Returns the channels of a JOIN paired with their keys by position. Channels past the end
+    /// of the key list get `None`. `JOIN 0`, which parts all channels, yields `[("0", None)]`.
+    pub fn join_channels(&self) -> Option<Vec<(&str, Option<&str>)>> {
+        if !self.command.matches("JOIN") {
+            return None;
+        }
+        let channels = self.params.first()?;
+        let mut keys = self.params.get(1).map(|keys| keys.split(','));
+        Some(channels.split(',')
+            .map(|channel| (channel, keys.as_mut().and_then(|keys| keys.next())))
+            .collect())
+    }
+    pub fn is_ping(&self) -> bool {
+        self.command.matches("PING")
+    }
+    /// Returns the token of a PING to echo back, its first parameter whether or not it's a
+    /// trailing one. A second parameter names the server the PING is for, as in
+    /// `PING server1 server2`.
+    pub fn ping_token(&self) -> Option<&str> {
+        if !self.is_ping() {
+            return None;
+        }
+        self.params.first().map(|token| token.as_ref())
+    }
+    /// Builds the PONG answering a PING, echoing its token as the trailing parameter. Fails if
+    /// the token can't be sent, e.g. because it contains a NUL.
+    pub fn pong_reply(&self) -> Result<OwnedMessage, ParserError> {
+        let pong = MessageBuilder::new().command("PONG");
+        match self.ping_token() {
+            Some(token) => pong.trailing(token),
+            None => pong
+        }.build()
+    }
+    /// Returns who was kicked from where by a KICK. Comma-separated channels and nicks are paired
+    /// by position, and a single channel is shared by all of the nicks, as in RFC 2812.
+    pub fn kick_info(&self) -> Option<Vec<KickInfo<'_>>> {
+        if !self.command.matches("KICK") {
+            return None;
+        }
+        let (channels, nicks) = match self.params[..] {
+            [ref channels, ref nicks, ..] => (channels, nicks),
+            _ => return None
+        };
+        let reason = self.params.get(2).map(|reason| reason.as_ref());
+        let channels: Vec<&str> = channels.split(',').collect();
+        Some(nicks.split(',')
+            .enumerate()
+            .filter_map(|(i, nick)| {
+                let channel = if channels.len() == 1 { channels[0] } else { channels.get(i)? };
+                Some(KickInfo { channel, nick, reason })
+            })
+            .collect())
+    }
+    /// Returns where to answer a PRIVMSG or NOTICE: the channel it was sent to, or the sender's
+    /// nick when it was sent to us privately.
+    pub fn reply_target(&self) -> Option<&str> {
+        if !self.command.matches("PRIVMSG") && !self.command.matches("NOTICE") {
+            return None;
+        }
+        match self.params.first() {
+            Some(target) if is_channel(target) => Some(target),
+            Some(_) => self.sender_nick(),
+            None => None
+        }
+    }
+    /// Returns the account the sender logged into with an IRCv3 ACCOUNT message, or `Some(None)`
+    /// if they logged out, which is sent as `*`.
+    pub fn account_change(&self) -> Option<Option<&str>> {
+        if !self.command.matches("ACCOUNT") {
+            return None;
+        }
+        match self.params.first()?.as_ref() {
+            "*" => Some(None),
+            account => Some(Some(account))
+        }
+    }
+    /// Returns the away reason of the sender from an IRCv3 AWAY message, or `Some(None)` if
+    /// they're back.
+    pub fn away_status(&self) -> Option<Option<&str>> {
+        if !self.command.matches("AWAY") {
+            return None;
+        }
+        Some(self.params.first().map(|reason| reason.as_ref()).filter(|reason| !reason.is_empty()))
+    }
+    /// Returns the old and new nick of a user renaming themselves with NICK.
+    pub fn nick_change(&self) -> Option<(&str, &str)> {
+        match (&self.prefix, self.params.first()) {
+            (&Some(Prefix::User { nick, .. }), Some(new_nick)) if self.command.matches("NICK") => Some((nick, new_nick)),
+            _ => None
+        }
+    }
+    /// Returns the reason a user gave when quitting, if any.
+    pub fn quit_reason(&self) -> Option<&str> {
+        if !self.command.matches("QUIT") {
+            return None;
+        }
+        self.params.first().map(|reason| reason.as_ref()).filter(|reason| !reason.is_empty())
+    }
+    /// Returns the comma-separated channels of a PART and the reason given for leaving, if any.
+    pub fn part_info(&self) -> Option<(Vec<&str>, Option<&str>)> {
+        if !self.command.matches("PART") {
+            return None;
+        }
+        let channels = self.params.first()?.split(',').filter(|channel| !channel.is_empty()).collect();
+        let reason = self.params.get(1).map(|reason| reason.as_ref()).filter(|reason| !reason.is_empty());
+        Some((channels, reason))
+    }
+    /// Returns the reason of an ERROR, which servers send right before closing the connection.
+    pub fn error_text(&self) -> Option<&str> {
+        if !self.command.matches("ERROR") {
+            return None;
+        }
+        self.params.last().map(|reason| reason.as_ref())
+    }
+    /// Returns the text of a WALLOPS, which is broadcast to operators.
+    pub fn wallops_text(&self) -> Option<&str> {
+        if !self.command.matches("WALLOPS") {
+            return None;
+        }
+        self.params.last().map(|text| text.as_ref())
+    }
+    /// Returns the invited nick and the channel of an INVITE.
+    pub fn invite_info(&self) -> Option<(&str, &str)> {
+        match self.params[..] {
+            [ref nick, ref channel, ..] if self.command.matches("INVITE") => Some((nick, channel)),
+            _ => None
+        }
+    }
+    /// Returns the channel of a TOPIC command and the topic it sets, or `None` for the topic if
+    /// it only queries it. An empty topic clears it.
+    pub fn topic_command(&self) -> Option<(&str, Option<&str>)> {
+        match self.params[..] {
+            [ref channel] if self.command.matches("TOPIC") => Some((channel, None)),
+            [ref channel, ref topic, ..] if self.command.matches("TOPIC") => Some((channel, Some(topic))),
+            _ => None
+        }
+    }
+    /// Returns the SASL payload of an AUTHENTICATE.
+    pub fn sasl_payload(&self) -> Option<SaslChunk<'_>> {
+        if !self.command.matches("AUTHENTICATE") {
+            return None;
+        }
+        Some(match self.params.first()?.as_ref() {
+            "+" => SaslChunk::Empty,
+            "*" => SaslChunk::Abort,
+            base64 => SaslChunk::Data { base64, more: base64.len() == SASL_CHUNK_LENGTH }
+        })
+    }
+    /// Returns every channel the params reference, with the default channel prefixes.
+    pub fn channels(&self) -> Vec<&str> {
+        self.channels_with_types(DEFAULT_CHANTYPES)
+    }
+    /// Returns every channel the params reference, splitting comma-separated lists and checking
+    /// names against `chantypes`, as advertised by ISUPPORT CHANTYPES. A trailing parameter is
+    /// free-form text and is skipped, except for the channels some servers send that way in
+    /// JOIN, INVITE and a PART without a reason.
+    pub fn channels_with_types(&self, chantypes: &str) -> Vec<&str> {
+        let trailing_is_target = self.command.matches("JOIN")
+            || self.command.matches("INVITE")
+            || self.command.matches("PART") && self.params.len() == 1;
+        let skip_text = self.has_trailing && !trailing_is_target;
+        let end = self.params.len().saturating_sub(usize::from(skip_text));
+        self.params[..end].iter()
+            .flat_map(|param| param.split(','))
+            .filter(|target| is_channel_with_types(target, chantypes))
+            .collect()
+    }
+    /// Returns the CTCP message in the trailing parameter of a PRIVMSG or NOTICE.
+    /// Some clients omit the closing `\x01`, so it's optional.
+    pub fn ctcp(&self) -> Option<Ctcp<'_>> {
+        if !self.command.matches("PRIVMSG") && !self.command.matches("NOTICE") {
+            return None;
+        }
+        let body = self.params.last()?.strip_prefix('\x01')?;
+        let body = body.strip_suffix('\x01').unwrap_or(body);
+        let (command, params) = body.split_once(' ').unwrap_or((body, ""));
+        if command.is_empty() {
+            return None;
+        }
+        Some(Ctcp { command, params })
+    }
+    /// Returns the value of the tag `key` as it appeared on the wire, still escaped. Like with
+    /// `tag`, an empty value like `key=` counts as none.
+    pub fn raw_tag(&self, key: &str) -> Option<&'a str> {
+        self.tag(key).flatten()
+    }
+    /// Looks up the tag `key`, which must include any `+` or vendor prefix. Returns `Some(None)`
+    /// if the tag is present without a value; an empty value like `key=` counts as none.
+    pub fn tag(&self, key: &str) -> Option<Option<&'a str>> {
+        self.tags.iter()
+            .find(|&&(k, _)| k == key)
+            .map(|&(_, value)| value.filter(|value| !value.is_empty()))
+    }
+    /// Whether the tag `key` is present, with or without a value.
+    pub fn has_tag(&self, key: &str) -> bool {
+        self.tag(key).is_some()
+    }
+    /// Returns the client-only tags, whose keys start with `+`.
+    pub fn client_only_tags(&self) -> impl Iterator<Item = &(&'a str, Option<&'a str>)> {
+        self.tags.iter().filter(|&&(key, _)| is_client_only_tag(key))
+    }
+    /// Returns the tags set by the server, i.e. all but the client-only ones.
+    pub fn server_tags(&self) -> impl Iterator<Item = &(&'a str, Option<&'a str>)> {
+        self.tags.iter().filter(|&&(key, _)| !is_client_only_tag(key))
+    }
+    /// Returns the value of the tag `key` with IRCv3 escapes resolved, or `None` for a tag
+    /// without a value, as with `raw_tag`.
+    pub fn tag_value(&self, key: &str) -> Option<Cow<'a, str>> {
+        self.raw_tag(key).map(unescape_tag_value)
+    }
+    /// Returns the IRCv3 `msgid` tag identifying the message.
+    pub fn msgid(&self) -> Option<Cow<'a, str>> {
+        self.tag_value("msgid")
+    }
+    /// Returns the IRCv3 `time` tag, e.g. `2021-07-21T19:20:00.123Z`, as unix time in
+    /// milliseconds. Returns `None` if the tag is missing or isn't a valid UTC timestamp.
+    pub fn server_time_millis(&self) -> Option<i64> {
+        parse_server_time(self.raw_tag("time")?)
+    }
+    /// Returns the IRCv3 `batch` tag naming the batch a message belongs to.
+    pub fn batch(&self) -> Option<Cow<'a, str>> {
+        self.tag_value("batch")
+    }
+    /// Returns the IRCv3 `label` tag tying a labeled response to its request.
+    pub fn label(&self) -> Option<Cow<'a, str>> {
+        self.tag_value("label")
+    }
+}
+
+/// Length of a full chunk of a SASL payload. Payloads are split into chunks of this length, and
+/// one that ends on a chunk boundary is followed by an empty chunk.
+pub const SASL_CHUNK_LENGTH: usize = 400;
+
+/// A chunk of a SASL payload sent with AUTHENTICATE.
+#[derive(PartialEq, Debug)]
+pub enum SaslChunk<'a> {
+    /// `+`, an empty payload or the end of one that filled its last chunk
+    Empty,
+    /// `*`, aborting the authentication
+    Abort,
+    /// Base64 data, with `more` set when the chunk is full and so the payload continues
+    Data { base64: &'a str, more: bool }
+}
+
+/// A single user kicked from a channel by a KICK.
+#[derive(PartialEq, Debug)]
+pub struct KickInfo<'a> {
+    pub channel: &'a str,
+    pub nick: &'a str,
+    pub reason: Option<&'a str>
+}
+
+/// A CTCP request or reply carried in a PRIVMSG or NOTICE, e.g. `\x01ACTION waves\x01`.
+#[derive(PartialEq, Debug)]
+pub struct Ctcp<'a> {
+    pub command: &'a str,
+    pub params: &'a str
+}
+
+/// A DCC offer carried in a CTCP, e.g. `\x01DCC SEND file.txt 3232235777 5000 1024\x01`.
+#[derive(PartialEq, Debug)]
+pub enum Dcc<'a> {
+    Send { filename: &'a str, ip: Ipv4Addr, port: u16, size: Option<u64> },
+    Chat { ip: Ipv4Addr, port: u16 },
+    /// A request to resume a SEND from `position`, which has no IP address
+    Resume { filename: &'a str, port: u16, position: u64 }
+}
+
+/// Parses a DCC SEND, CHAT or RESUME offer. The IP address is sent as a single decimal integer
+/// and filenames with spaces are wrapped in double quotes. Returns `None` for other CTCPs.
+pub fn parse_dcc<'a>(ctcp: &Ctcp<'a>) -> Option<Dcc<'a>> {
+    if !ctcp.command.eq_ignore_ascii_case("DCC") {
+        return None;
+    }
+    let (kind, rest) = ctcp.params.split_once(' ')?;
+    let (argument, rest) = match rest.strip_prefix('"') {
+        Some(quoted) => {
+            let (filename, rest) = quoted.split_once('"')?;
+            (filename, rest.trim_start_matches(' '))
+        },
+        None => rest.split_once(' ')?
+    };
+    let mut fields = rest.split(' ').filter(|field| !field.is_empty());
+    let ip = |field: &str| field.parse::<u32>().ok().map(Ipv4Addr::from);
+    let dcc = if kind.eq_ignore_ascii_case("SEND") {
+        Dcc::Send {
+            filename: argument,
+            ip: ip(fields.next()?)?,
+            port: fields.next()?.parse().ok()?,
+            size: match fields.next() {
+                Some(size) => Some(size.parse().ok()?),
+                None => None
+            }
+        }
+    } else if kind.eq_ignore_ascii_case("CHAT") {
+        Dcc::Chat { ip: ip(fields.next()?)?, port: fields.next()?.parse().ok()? }
+    } else if kind.eq_ignore_ascii_case("RESUME") {
+        Dcc::Resume { filename: argument, port: fields.next()?.parse().ok()?, position: fields.next()?.parse().ok()? }
+    } else {
+        return None;
+    };
+    Some(dcc)
+}
+
+impl<'a> fmt::Display for Message<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        self.write_wire(f, "\r\n")
+    }
+}
+// Lets to_bytes serialize through write_wire without a String in between
+struct ByteWriter<'v>(&'v mut Vec<u8>);
+impl<'v> fmt::Write for ByteWriter<'v> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        self.0.extend_from_slice(s.as_bytes());
+        Ok(())
+    }
+}
+impl<'a> Message<'a> {
+    // Shared by Display and to_wire_string_with so they can only differ in the terminator
+    fn write_wire<W: fmt::Write>(&self, f: &mut W, terminator: &str) -> fmt::Result {
+        if !self.tags.is_empty() {
+            f.write_str("@")?;
+            for (i, &(key, value)) in self.tags.iter().enumerate() {
+                if i > 0 {
+                    f.write_str(";")?;
+                }
+                f.write_str(key)?;
+                if let Some(value) = value {
+                    write!(f, "={}", value)?;
+                }
+            }
+            f.write_str(" ")?;
+        }
+        if let Some(ref prefix) = self.prefix {
+            write!(f, ":{} ", prefix)?;
+        }
+        write!(f, "{}", self.command)?;
+        if let Some((last, middle)) = self.params.split_last() {
+            for param in middle {
+                write!(f, " {}", param)?;
+            }
+            // The last parameter also needs the colon if it couldn't be read back as a middle parameter
+            if self.has_trailing || last.is_empty() || last.contains(' ') || last.starts_with(':') {
+                write!(f, " :{}", last)?;
+            } else {
+                write!(f, " {}", last)?;
+            }
+        }
+        f.write_str(terminator)
+    }
+}
+
+/// A prefix that owns its data, see `Prefix`.
+#[derive(PartialEq, Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum OwnedPrefix {
+    User { nick: String, user: Option<String>, host: Option<String> },
+    Server(String)
+}
+impl<'a> From<Prefix<'a>> for OwnedPrefix {
+    fn from(prefix: Prefix<'a>) -> OwnedPrefix {
+        prefix.to_owned()
+    }
+}
+
+/// A command that owns its data, see `Command`.
+#[derive(PartialEq, Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(tag = "type", content = "value"))]
+pub enum OwnedCommand {
+    Named(String),
+    Numeric(u16)
+}
+impl<'a> From<Command<'a>> for OwnedCommand {
+    fn from(command: Command<'a>) -> OwnedCommand {
+        command.to_owned()
+    }
+}
+
+/// A message that doesn't borrow the input it was parsed from, see `Message`.
+#[derive(PartialEq, Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct OwnedMessage {
+    pub tags: Vec<(String, Option<String>)>,
+    pub prefix: Option<OwnedPrefix>,
+    pub command: OwnedCommand,
+    pub params: Vec<String>,
+    pub has_trailing: bool
+}
+impl OwnedMessage {
+    /// Borrows the message as a `Message`.
+    pub fn as_message(&self) -> Message<'_> {
+        Message {
+            tags: self.tags.iter()
+                .map(|(key, value)| (key.as_str(), value.as_deref()))
+                .collect(),
+            prefix: self.prefix.as_ref().map(|prefix| match *prefix {
+                OwnedPrefix::User { ref nick, ref user, ref host } => Prefix::User {
+                    nick,
+                    user: user.as_deref(),
+                    host: host.as_deref()
+                },
+                OwnedPrefix::Server(ref serverstr) => Prefix::Server(serverstr)
+            }),
+            command: match self.command {
+                OwnedCommand::Named(ref s) => Command::Named(Cow::Borrowed(s)),
+                OwnedCommand::Numeric(n) => Command::Numeric(n)
+            },
+            params: self.params.iter().map(|p| Cow::Borrowed(p.as_str())).collect(),
+            has_trailing: self.has_trailing,
+            raw: Cow::Borrowed(""),
+            raw_bytes: b""
+        }
+    }
+}
+impl<'a> From<Message<'a>> for OwnedMessage {
+    fn from(msg: Message<'a>) -> OwnedMessage {
+        msg.to_owned()
+    }
+}
+impl fmt::Display for OwnedMessage {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        self.as_message().fmt(f)
+    }
+}
+
+/// Parses a single line into an owned message. The line terminator is optional, so lines from
+/// e.g. `BufRead::lines` can be parsed as is, but anything after it is an error.
+impl FromStr for OwnedMessage {
+    type Err = ParserError;
+    fn from_str(s: &str) -> Result<OwnedMessage, ParserError> {
+        let (msg, rest) = parse_unterminated_line(s.as_bytes())?;
+        if !rest.is_empty() {
+            return Err(ParserError::TrailingInput { position: s.len() - rest.len() });
+        }
+        Ok(msg.to_owned())
+    }
+}
+
+/// Builds outgoing messages, e.g.
+/// `MessageBuilder::new().command("PRIVMSG").param("#chan").trailing("hello world").build()`.
+#[derive(Debug, Default, Clone)]
+pub struct MessageBuilder {
+    prefix: Option<OwnedPrefix>,
+    command: Option<OwnedCommand>,
+    params: Vec<String>,
+    trailing: Option<String>
+}
+impl MessageBuilder {
+    pub fn new() -> MessageBuilder {
+        MessageBuilder::default()
+    }
+    pub fn command(mut self, command: &str) -> MessageBuilder {
+        self.command = Some(OwnedCommand::Named(command.to_string()));
+        self
+    }
+    pub fn numeric(mut self, numeric: u16) -> MessageBuilder {
+        self.command = Some(OwnedCommand::Numeric(numeric));
+        self
+    }
+    pub fn prefix(mut self, prefix: Prefix) -> MessageBuilder {
+        self.prefix = Some(prefix.to_owned());
+        self
+    }
+    pub fn param(mut self, param: &str) -> MessageBuilder {
+        self.params.push(param.to_string());
+        self
+    }
+    /// Sets the trailing parameter, which is always serialized after the other parameters behind a `:`.
+    pub fn trailing(mut self, trailing: &str) -> MessageBuilder {
+        self.trailing = Some(trailing.to_string());
+        self
+    }
+    /// Builds the message.
+    ///
+    /// Fails with `ParserError::MissingCommand` if no command was set, and with
+    /// `ParserError::MalformedCommand` if it's neither all ASCII letters nor a numeric up to 999.
+    /// Fails with `ParserError::MalformedPrefix` if a part of the prefix contains a space, CR, LF
+    /// or NUL. Fails with `ParserError::InvalidParam` if a parameter before the last one is
+    /// empty, starts with `:` or contains a space, since it would be read back as different
+    /// parameters, or if any parameter contains CR, LF or NUL, which would end the line early.
+    pub fn build(self) -> Result<OwnedMessage, ParserError> {
+        let command = self.command.ok_or(ParserError::MissingCommand { position: 0 })?;
+        match command {
+            OwnedCommand::Named(ref command) => check_command(command)?,
+            OwnedCommand::Numeric(numeric) if numeric > 999 => return Err(ParserError::MalformedCommand { position: 0 }),
+            OwnedCommand::Numeric(_) => {}
+        }
+        if let Some(ref prefix) = self.prefix {
+            check_prefix(prefix)?;
+        }
+        let has_trailing = self.trailing.is_some();
+        let mut params = self.params;
+        params.extend(self.trailing);
+        check_params(&params)?;
+        Ok(OwnedMessage {
+            tags: Vec::new(),
+            prefix: self.prefix,
+            command,
+            params,
+            has_trailing
+        })
+    }
+}
+
+// Named commands to send are all letters, like the ones the parser accepts
+fn check_command(command: &str) -> Result<(), ParserError> {
+    if command.is_empty() || !command.bytes().all(|b| b.is_ascii_alphabetic()) {
+        return Err(ParserError::MalformedCommand { position: 0 });
+    }
+    Ok(())
+}
+fn check_prefix(prefix: &OwnedPrefix) -> Result<(), ParserError> {
+    let parts = match *prefix {
+        OwnedPrefix::User { ref nick, ref user, ref host } => [Some(nick), user.as_ref(), host.as_ref()],
+        OwnedPrefix::Server(ref server) => [Some(server), None, None]
+    };
+    if parts.iter().flatten().any(|part| part.contains([' ', '\r', '\n', '\0'])) {
+        return Err(ParserError::MalformedPrefix { position: 0 });
+    }
+    Ok(())
+}
+
+// Checks that `params` are read back as the same parameters once sent. The last one gets a
+// colon whenever it needs one, so only line-ending characters are a problem there.
+fn check_params<S: AsRef<str>>(params: &[S]) -> Result<(), ParserError> {
+    let last = params.len().saturating_sub(1);
+    match params.iter().map(|param| param.as_ref()).enumerate()
+        .position(|(index, param)| param.contains(['\r', '\n', '\0']) || (index < last && !is_middle_param(param))) {
+        Some(index) => Err(ParserError::InvalidParam { index }),
+        None => Ok(())
+    }
+}
+// Whether `param` can be sent without a colon and read back as the same single parameter
+fn is_middle_param(param: &str) -> bool {
+    !param.is_empty() && !param.starts_with(':') && !param.contains(' ')
+}
+
+/// Splits `text` into as many `PRIVMSG target :text` lines as it takes to keep each of them,
+/// CRLF included, within `MAX_LINE_LENGTH` once the server has prepended our `:nick!user@host `
+/// source, whose length without the colon and space is `source_len`. Text is only split between
+/// characters, so multibyte UTF-8 sequences stay intact. Each line of a multi-line `text` is sent
+/// separately, so CR and LF never end up on the wire, and empty lines are skipped.
+///
+/// Fails with `ParserError::InvalidParam` if `target` or `text` can't be sent, and with
+/// `ParserError::LineTooLong` if not even a single character of `text` fits on a line.
+pub fn split_privmsg(target: &str, text: &str, source_len: usize) -> Result<Vec<String>, ParserError> {
+    check_params(&[target, ""])?;
+    let overhead = ":".len() + source_len + " PRIVMSG ".len() + target.len() + " :".len() + "\r\n".len();
+    let mut lines = Vec::new();
+    for line in text.split(['\r', '\n']) {
+        split_privmsg_line(target, line, overhead, &mut lines)?;
+    }
+    Ok(lines)
+}
+fn split_privmsg_line(target: &str, text: &str, overhead: usize, lines: &mut Vec<String>) -> Result<(), ParserError> {
+    let available = MAX_LINE_LENGTH.saturating_sub(overhead);
+    let mut rest = text;
+    while let Some(first) = rest.chars().next() {
+        let mut end = rest.len().min(available);
+        while !rest.is_char_boundary(end) {
+            end -= 1;
+        }
+        if end == 0 {
+            return Err(ParserError::LineTooLong { len: overhead + first.len_utf8() });
+        }
+        let (chunk, tail) = rest.split_at(end);
+        lines.push(MessageBuilder::new().command("PRIVMSG").param(target).trailing(chunk).build()?.to_string());
+        rest = tail;
+    }
+    Ok(())
+}
+
+fn message_parser(input: &[u8]) -> ParseResult<'_, Message<'_>> {
+    let mut msg = Message::default();
+    let rest = message_parser_into(input, &mut msg, false, true)?;
+    Ok((rest, msg))
+}
+// Fills `msg` in place, reusing the buffers it already has
+fn message_parser_into<'a>(input: &'a [u8], msg: &mut Message<'a>, lenient: bool, require_eol: bool) -> Result<&'a [u8], nom::Err<Failure<'a>>> {
+    let prefix = if lenient { lenient_prefix } else { optional_prefix };
+    let line_end = if require_eol { eol } else { eol_or_end };
+    let (rest, (parsed_tags, parsed_prefix, parsed_command, parsed_params)) =
+        tuple((opt(tags_parser), prefix, command_parser, line_end))(input)?;
+    msg.tags.clear();
+    if let Some(tags) = parsed_tags {
+        split_tags_into(tags, &mut msg.tags);
+    }
+    msg.prefix = parsed_prefix;
+    msg.command = parsed_command;
+    msg.params.clear();
+    msg.has_trailing = parse_params_into(parsed_params, &mut msg.params);
+    let (end, _) = find_line_end(input).unwrap_or((input.len(), input.len()));
+    msg.raw_bytes = &input[..end];
+    msg.raw = String::from_utf8_lossy(msg.raw_bytes);
+    Ok(rest)
+}
+
+// Splits the middle parameters on runs of spaces, so lenient servers sending several of them
+// never produce empty parameters. Only the space byte separates parameters, so tabs are kept.
+// The trailing parameter is taken verbatim, spaces included, from the first parameter starting
+// with a colon to the end of the line, even when it's empty.
+// Parameters are decoded one by one so that a stray invalid byte only affects its own parameter.
+fn parse_params_into<'a>(line: &'a [u8], params: &mut Vec<Cow<'a, str>>) -> bool {
+    let mut rest = line;
+    while let Some(start) = rest.iter().position(|&b| b != b' ') {
+        rest = &rest[start..];
+        if rest[0] == b':' {
+            params.push(String::from_utf8_lossy(&rest[1..]));
+            return true;
+        }
+        let end = rest.iter().position(|&b| b == b' ').unwrap_or(rest.len());
+        params.push(String::from_utf8_lossy(&rest[..end]));
+        rest = &rest[end..];
+    }
+    false
+}
+
+fn command_parser(input: &[u8]) -> ParseResult<'_, Command<'_>> {
+    if input.is_empty() {
+        return Err(nom::Err::Incomplete(Needed::Unknown));
+    }
+    let (rest, cmd) = map_res(is_not(" \r\n"), from_utf8)(input).map_err(|e| e.map(|failure: Failure<'_>| match failure.kind {
+        FailureKind::Nom(_) => Failure { input, kind: FailureKind::MissingCommand },
+        _ => failure
+    }))?;
+    // Numerics are exactly three digits and named commands are all letters
+    match FromStr::from_str(cmd) {
+        Ok(numericcmd) if cmd.len() == 3 && cmd.bytes().all(|b| b.is_ascii_digit()) => Ok((rest, Command::Numeric(numericcmd))),
+        _ if cmd.bytes().all(|b| b.is_ascii_alphabetic()) => Ok((rest, Command::Named(cmd.into()))),
+        _ => Err(nom::Err::Error(Failure { input, kind: FailureKind::MalformedCommand }))
+    }
+}
+
+// A leading colon commits to parsing a prefix, so a broken one is an error rather than a missing
+// prefix. Either the whole prefix is consumed or none of the input is.
+fn optional_prefix(input: &[u8]) -> ParseResult<'_, Option<Prefix<'_>>> {
+    if !input.starts_with(b":") {
+        return Ok((input, None));
+    }
+    match prefix_parser(input) {
+        Ok((rest, prefix)) => Ok((rest, Some(prefix))),
+        Err(e) => Err(e.map(|failure| match failure.kind {
+            FailureKind::InvalidUtf8 { .. } => failure,
+            _ => Failure { input, kind: FailureKind::MalformedPrefix }
+        }))
+    }
+}
+// Also takes a prefix missing its colon when the first token is a full nick!user@host, which
+// can't be mistaken for a command. Bare server names are left alone as they're too ambiguous.
+fn lenient_prefix(input: &[u8]) -> ParseResult<'_, Option<Prefix<'_>>> {
+    if input.starts_with(b":") {
+        return optional_prefix(input);
+    }
+    match terminated(host_parser, spaces1)(input) {
+        Ok((rest, (nick, Some(user), Some(host)))) => Ok((rest, Some(Prefix::User { nick, user: Some(user), host: Some(host) }))),
+        _ => Ok((input, None))
+    }
+}
+fn prefix_parser(input: &[u8]) -> ParseResult<'_, Prefix<'_>> {
+    // The space is optional so that a line holding only a prefix fails on its missing command
+    delimited(tag(":"), map_opt(word_parser, classify_prefix), spaces0)(input)
+}
+fn classify_prefix(prefix: &str) -> Option<Prefix<'_>> {
+    // A second colon would otherwise end up in the nick or server name
+    if prefix.starts_with(':') {
+        return None;
+    }
+    if is_server_name(prefix) {
+        return Some(Prefix::Server(prefix));
+    }
+    match host_parser(prefix.as_bytes()) {
+        Ok((b"", (nick, user, host))) => Some(Prefix::User { nick, user, host }),
+        _ => None
+    }
+}
+fn host_parser(input: &[u8]) -> ParseResult<'_, (&str, Option<&str>, Option<&str>)> {
+    tuple((
+        map_res(is_not("!@ "), from_utf8),
+        opt(preceded(tag("!"), map_res(is_not("@ "), from_utf8))),
+        opt(preceded(tag("@"), map_res(is_not(" "), from_utf8)))
+    ))(input)
+}
+
+// Nicknames can't contain dots, so a dotted prefix without user or host parts is a server
+fn is_server_name(prefix: &str) -> bool {
+    prefix.contains('.') && !prefix.contains(['!', '@'])
+}
+
+pub fn parse_message(input: &str) -> Result<Message<'_>, ParserError> {
+    parse_message_with_rest(input).map(|(msg, _)| msg)
+}
+
+/// Parses a message like `parse_message`, but also accepts a `nick!user@host` prefix without its
+/// leading colon, as sent by some buggy services and relays. Only a first token with both `!`
+/// and `@` counts as a prefix, so anything `parse_message` accepts parses the same way.
+pub fn parse_message_lenient(input: &str) -> Result<Message<'_>, ParserError> {
+    let input = input.as_bytes();
+    check_line_length(input)?;
+    let mut msg = Message::default();
+    message_parser_into(input, &mut msg, true, true).map_err(|e| ParserError::from_nom(input, e))?;
+    Ok(msg)
+}
+/// Parses a single line like `parse_message`, but also accepts one whose terminator was already
+/// stripped, e.g. by `str::lines`. Use `parse_message` for raw stream input, where a missing
+/// terminator means the line is incomplete.
+pub fn parse_message_line(input: &str) -> Result<Message<'_>, ParserError> {
+    parse_unterminated_line(input.as_bytes()).map(|(msg, _)| msg)
+}
+fn parse_unterminated_line(input: &[u8]) -> Result<(Message<'_>, &[u8]), ParserError> {
+    check_line_length(input)?;
+    let mut msg = Message::default();
+    // Without a terminator to wait for, running out of input means the command is missing
+    let rest = message_parser_into(input, &mut msg, false, false).map_err(|e| match e {
+        nom::Err::Incomplete(_) => ParserError::MissingCommand { position: input.len() },
+        e => ParserError::from_nom(input, e)
+    })?;
+    Ok((msg, rest))
+}
+/// Parses the first message in `input` and returns it along with the input left after its line.
+pub fn parse_message_with_rest(input: &str) -> Result<(Message<'_>, &str), ParserError> {
+    let (msg, rest) = parse_line(input.as_bytes())?;
+    Ok((msg, &input[input.len() - rest.len()..]))
+}
+
+/// Parses a message from raw bytes. Parameters that aren't valid UTF-8 are decoded
+/// lossily, while the tags, prefix and command must be valid UTF-8.
+pub fn parse_message_bytes(input: &[u8]) -> Result<Message<'_>, ParserError> {
+    parse_line(input).map(|(msg, _)| msg)
+}
+
+fn parse_line(input: &[u8]) -> Result<(Message<'_>, &[u8]), ParserError> {
+    check_line_length(input)?;
+    match message_parser(input) {
+        Ok((rest, msg)) => Ok((msg, rest)),
+        Err(e) => Err(ParserError::from_nom(input, e))
+    }
+}
+// The tags and the rest of the line have separate limits, so an oversized line is rejected
+// before any of it is parsed. A line without its terminator still needs room for the CRLF.
+fn check_line_length(input: &[u8]) -> Result<(), ParserError> {
+    let (line_len, missing_crlf) = find_line_end(input).map_or((input.len(), 2), |(_, next)| (next, 0));
+    let tags_len = if input.starts_with(b"@") {
+        input[..line_len].iter().position(|&b| b == b' ').map_or(line_len, |space| space + 1)
+    } else {
+        0
+    };
+    if tags_len > MAX_TAGS_LENGTH {
+        return Err(ParserError::TagsTooLong { len: tags_len });
+    }
+    let len = line_len + missing_crlf - tags_len;
+    if len > MAX_LINE_LENGTH {
+        return Err(ParserError::LineTooLong { len });
+    }
+    Ok(())
+}
+
+/// Parses the first message of `input` into `msg` like `parse_message`, but reuses the tag and
+/// parameter buffers `msg` already has, so parsing in a loop doesn't allocate for every line.
+pub fn parse_into<'a>(msg: &mut Message<'a>, input: &'a str) -> Result<(), ParserError> {
+    let input = input.as_bytes();
+    check_line_length(input)?;
+    message_parser_into(input, msg, false, true)
+        .map(|_| ())
+        .map_err(|e| ParserError::from_nom(input, e))
+}
+
+/// Parses every line in `input`, yielding a result per line. Empty lines are skipped
+/// and a trailing line without a terminator results in an error.
+pub fn parse_messages(input: &str) -> Vec<Result<Message<'_>, ParserError>> {
+    let mut ret = Vec::new();
+    let mut input = input;
+    while !input.is_empty() {
+        let (end, next) = find_line_end(input.as_bytes()).unwrap_or((input.len(), input.len()));
+        let (line, rest) = input.split_at(next);
+        if end > 0 {
+            ret.push(parse_message(line));
+        }
+        input = rest;
+    }
+    ret
+}
+
+/// Buffers raw bytes read off a connection and yields messages as complete lines arrive.
+/// Lines that fail to parse are skipped. A line ending in a bare CR at the end of the
+/// buffer is held back until it's known whether a LF follows. A partial line that grows past
+/// `MAX_TAGS_LENGTH + MAX_LINE_LENGTH` bytes is dropped along with the rest of it, so a peer
+/// that never ends its line can't grow the buffer without bound.
+#[derive(Debug, Default)]
+pub struct MessageStream {
+    buffer: Vec<u8>,
+    // Set while skipping the rest of a dropped line
+    discarding: bool,
+    // The length of the last dropped line, for MessageReader to report
+    dropped: Option<usize>
+}
+impl MessageStream {
+    pub fn new() -> MessageStream {
+        MessageStream::default()
+    }
+    pub fn push(&mut self, data: &[u8]) {
+        let mut data = data;
+        if self.discarding {
+            match data.iter().position(|&b| b == b'\r' || b == b'\n') {
+                Some(end) => {
+                    data = &data[end..];
+                    self.discarding = false;
+                }
+                None => return
+            }
+        }
+        self.buffer.extend_from_slice(data);
+        let partial = self.buffer.iter().rposition(|&b| b == b'\r' || b == b'\n').map_or(0, |end| end + 1);
+        if self.buffer.len() - partial > MAX_TAGS_LENGTH + MAX_LINE_LENGTH {
+            self.dropped = Some(self.buffer.len() - partial);
+            self.buffer.truncate(partial);
+            self.discarding = true;
+        }
+    }
+    pub fn next_message(&mut self) -> Option<OwnedMessage> {
+        while let Some(line) = self.next_line() {
+            if let Ok(msg) = parse_message_bytes(&line) {
+                return Some(msg.to_owned());
+            }
+        }
+        None
+    }
+    // Removes the next complete line from the buffer, terminator included
+    fn next_line(&mut self) -> Option<Vec<u8>> {
+        let (_, next) = find_line_end(&self.buffer)?;
+        if next == self.buffer.len() && self.buffer.ends_with(b"\r") {
+            return None;
+        }
+        Some(self.buffer.drain(..next).collect())
+    }
+}
+
+/// Reads messages off any `Read`, such as a `TcpStream`, e.g. `for msg in MessageReader::new(stream)`.
+/// Unlike `MessageStream`, lines that fail to parse are yielded as errors, and so is an
+/// unterminated line at the end of the input. Empty lines are skipped.
+#[cfg(feature = "std")]
+#[derive(Debug)]
+pub struct MessageReader<R> {
+    reader: R,
+    stream: MessageStream,
+    eof: bool
+}
+#[cfg(feature = "std")]
+impl<R: std::io::Read> MessageReader<R> {
+    pub fn new(reader: R) -> MessageReader<R> {
+        MessageReader {
+            reader,
+            stream: MessageStream::new(),
+            eof: false
+        }
+    }
+    pub fn into_inner(self) -> R {
+        self.reader
+    }
+}
+#[cfg(feature = "std")]
+impl<R: std::io::Read> Iterator for MessageReader<R> {
+    type Item = Result<OwnedMessage, ParserError>;
+    fn next(&mut self) -> Option<Result<OwnedMessage, ParserError>> {
+        let mut chunk = [0; 4096];
+        loop {
+            let line = match self.stream.next_line() {
+                Some(line) => line,
+                // A line ending in a bare CR can't be continued once the input has ended
+                None if self.eof && self.stream.buffer.ends_with(b"\r") => std::mem::take(&mut self.stream.buffer),
+                None if self.eof && self.stream.buffer.is_empty() => return None,
+                None if self.eof => {
+                    self.stream.buffer.clear();
+                    return Some(Err(ParserError::Incomplete));
+                }
+                None => {
+                    match self.reader.read(&mut chunk) {
+                        Ok(0) => self.eof = true,
+                        Ok(n) => {
+                            self.stream.push(&chunk[..n]);
+                            if let Some(len) = self.stream.dropped.take() {
+                                return Some(Err(ParserError::LineTooLong { len }));
+                            }
+                        }
+                        Err(ref e) if e.kind() == std::io::ErrorKind::Interrupted => {}
+                        Err(e) => return Some(Err(ParserError::Io(e.kind())))
+                    }
+                    continue;
+                }
+            };
+            if !line.starts_with(b"\r") && !line.starts_with(b"\n") {
+                return Some(parse_message_bytes(&line).map(|msg| msg.to_owned()));
+            }
+        }
+    }
+}
+
+/// Splits the tokens of an RPL_ISUPPORT (005) reply into keys and optional values, e.g.
+/// `NICKLEN=30` into `("NICKLEN", Some("30"))`. Returns nothing for other messages.
+pub fn parse_isupport<'a>(msg: &'a Message<'_>) -> Vec<(&'a str, Option<&'a str>)> {
+    let args = match msg.numeric_args() {
+        Some(args) if msg.command == Command::Numeric(5) => args,
+        _ => return Vec::new()
+    };
+    // The trailing parameter is human-readable text
+    let end = if msg.has_trailing { args.len().saturating_sub(1) } else { args.len() };
+    args[..end].iter()
+        .map(|token| match token.split_once('=') {
+            Some((key, value)) => (key, Some(value)),
+            None => (token.as_ref(), None)
+        })
+        .collect()
+}
+
+/// Pairs the mode letters of an ISUPPORT `PREFIX` value like `(ohv)@%+` with their nick prefix
+/// sigils, in rank order. Returns `None` if the parentheses are missing or the counts differ.
+pub fn parse_prefix_token(value: &str) -> Option<Vec<(char, char)>> {
+    let (modes, sigils) = value.strip_prefix('(')?.split_once(')')?;
+    if modes.chars().count() != sigils.chars().count() {
+        return None;
+    }
+    Some(modes.chars().zip(sigils.chars()).collect())
+}
+
+/// The fields of an RPL_WHOREPLY (352) reply.
+#[derive(PartialEq, Debug)]
+pub struct WhoReply<'a> {
+    pub channel: &'a str,
+    pub user: &'a str,
+    pub host: &'a str,
+    pub server: &'a str,
+    pub nick: &'a str,
+    /// Away status (`H` or `G`), followed by `*` for IRC operators and channel status prefixes
+    pub flags: &'a str,
+    pub hopcount: u32,
+    pub realname: &'a str
+}
+
+/// Maps the positional parameters of an RPL_WHOREPLY (352) reply to their fields. The hopcount
+/// is the first word of the trailing parameter. Returns `None` for other or malformed messages.
+pub fn parse_who_reply<'a>(msg: &'a Message<'_>) -> Option<WhoReply<'a>> {
+    if msg.command != Command::Numeric(352) {
+        return None;
+    }
+    match *msg.numeric_args()? {
+        [ref channel, ref user, ref host, ref server, ref nick, ref flags, ref last] => {
+            let (hopcount, realname) = last.split_once(' ').unwrap_or((last, ""));
+            Some(WhoReply {
+                channel,
+                user,
+                host,
+                server,
+                nick,
+                flags,
+                hopcount: hopcount.parse().ok()?,
+                realname
+            })
+        }
+        _ => None
+    }
+}
+
+/// A channel listed by an RPL_LIST (322) reply.
+#[derive(PartialEq, Debug)]
+pub struct ListEntry<'a> {
+    pub channel: &'a str,
+    /// Number of visible users
+    pub users: u32,
+    pub topic: &'a str
+}
+
+/// Parses an RPL_LIST (322) reply. A missing topic is empty. Returns `None` for other messages
+/// or when the user count isn't a number.
+pub fn parse_list_reply<'a>(msg: &'a Message<'_>) -> Option<ListEntry<'a>> {
+    if msg.command != Command::Numeric(322) {
+        return None;
+    }
+    let (channel, users, topic) = match *msg.numeric_args()? {
+        [ref channel, ref users] => (channel, users, ""),
+        [ref channel, ref users, ref topic] => (channel, users, topic.as_ref()),
+        _ => return None
+    };
+    Some(ListEntry { channel, users: users.parse().ok()?, topic })
+}
+
+/// A key of IRCv3 metadata set on a user or channel by a METADATA message.
+#[derive(PartialEq, Debug)]
+pub struct Metadata<'a> {
+    pub target: &'a str,
+    pub key: &'a str,
+    /// `*` for metadata everyone can see, anything else is up to the server
+    pub visibility: &'a str,
+    /// The new value, or `None` when the key was removed
+    pub value: Option<&'a str>
+}
+
+/// Parses an IRCv3 `METADATA target key visibility [:value]` message. Returns `None` for other
+/// messages or when a parameter is missing.
+pub fn parse_metadata<'a>(msg: &'a Message<'_>) -> Option<Metadata<'a>> {
+    if !msg.command.matches("METADATA") {
+        return None;
+    }
+    match msg.params[..] {
+        [ref target, ref key, ref visibility] => Some(Metadata { target, key, visibility, value: None }),
+        [ref target, ref key, ref visibility, ref value] => Some(Metadata { target, key, visibility, value: Some(value) }),
+        _ => None
+    }
+}
+
+/// Parses an RPL_MONONLINE (730) or RPL_MONOFFLINE (731) reply into whether the targets came
+/// online and the targets themselves, given as `nick!user@host` or bare nicks. Returns `None`
+/// for other messages or when a target isn't a valid prefix.
+pub fn parse_mono<'a>(msg: &'a Message<'_>) -> Option<(bool, Vec<Prefix<'a>>)> {
+    let online = match msg.command {
+        Command::Numeric(730) => true,
+        Command::Numeric(731) => false,
+        _ => return None
+    };
+    match *msg.numeric_args()? {
+        [ref targets] => {
+            let targets = targets.split(',').filter(|target| !target.is_empty()).map(classify_prefix);
+            Some((online, targets.collect::<Option<_>>()?))
+        }
+        _ => None
+    }
+}
+
+/// The start or end of an IRCv3 batch of messages.
+#[derive(PartialEq, Debug)]
+pub enum BatchMarker<'a> {
+    /// `BATCH +reference type params...`, with the `+` stripped off the reference
+    Open { reference: &'a str, kind: &'a str, params: Vec<&'a str> },
+    /// `BATCH -reference`, with the `-` stripped off the reference
+    Close { reference: &'a str }
+}
+
+/// Tells whether a BATCH opens or closes the batch it references. Returns `None` for other
+/// messages and for references without a `+` or `-`.
+pub fn parse_batch<'a>(msg: &'a Message<'_>) -> Option<BatchMarker<'a>> {
+    if !msg.command.matches("BATCH") {
+        return None;
+    }
+    let marker = msg.params.first()?;
+    if let Some(reference) = marker.strip_prefix('+') {
+        Some(BatchMarker::Open {
+            reference,
+            kind: msg.params.get(1)?,
+            params: msg.params[2..].iter().map(|param| param.as_ref()).collect()
+        })
+    } else {
+        marker.strip_prefix('-').map(|reference| BatchMarker::Close { reference })
+    }
+}
+
+/// A channel topic reply, either RPL_TOPIC (332) or RPL_TOPICWHOTIME (333).
+#[derive(PartialEq, Debug)]
+pub enum Topic<'a> {
+    Text { channel: &'a str, text: &'a str },
+    /// Who set the topic, often as a full `nick!user@host` mask, and when as a string of unix time
+    SetBy { channel: &'a str, setter: &'a str, time: &'a str }
+}
+impl<'a> Topic<'a> {
+    pub fn channel(&self) -> &'a str {
+        match *self {
+            Topic::Text { channel, .. } | Topic::SetBy { channel, .. } => channel
+        }
+    }
+    /// Returns the time the topic was set as unix time, if it's a valid one.
+    pub fn set_time(&self) -> Option<u64> {
+        match *self {
+            Topic::SetBy { time, .. } => time.parse().ok(),
+            Topic::Text { .. } => None
+        }
+    }
+}
+
+/// Maps the parameters of an RPL_TOPIC (332) or RPL_TOPICWHOTIME (333) reply to their fields.
+/// Returns `None` for other or malformed messages.
+pub fn parse_topic<'a>(msg: &'a Message<'_>) -> Option<Topic<'a>> {
+    match (&msg.command, msg.numeric_args()?) {
+        (&Command::Numeric(332), [ref channel, ref text]) => Some(Topic::Text { channel, text }),
+        (&Command::Numeric(333), [ref channel, ref setter, ref time]) => Some(Topic::SetBy { channel, setter, time }),
+        _ => None
+    }
+}
+
+/// An IRCv3 capability negotiation reply, e.g. `CAP * LS :multi-prefix sasl`.
+#[derive(PartialEq, Debug)]
+pub struct CapMessage<'a> {
+    /// LS, LIST, ACK, NAK, NEW or DEL
+    pub subcommand: &'a str,
+    /// Our nick, or `*` before registration
+    pub target: &'a str,
+    /// The capabilities, with any `=value` left attached
+    pub caps: Vec<&'a str>,
+    /// Whether the list continues in another message, marked by a `*` before it
+    pub more: bool
+}
+
+/// Splits a CAP reply into its subcommand and capabilities, for both the `CAP target LS :caps`
+/// and the continued `CAP target LS * :caps` forms. Returns `None` for other messages.
+pub fn parse_cap<'a>(msg: &'a Message<'_>) -> Option<CapMessage<'a>> {
+    if !msg.command.matches("CAP") {
+        return None;
+    }
+    let (target, subcommand, caps, more) = match msg.params[..] {
+        [ref target, ref subcommand, ref caps] => (target, subcommand, caps, false),
+        [ref target, ref subcommand, ref marker, ref caps] if marker == "*" => (target, subcommand, caps, true),
+        _ => return None
+    };
+    Some(CapMessage {
+        subcommand,
+        target,
+        caps: caps.split(' ').filter(|cap| !cap.is_empty()).collect(),
+        more
+    })
+}
+
+/// Channel membership prefixes in rank order, used when the server doesn't advertise PREFIX.
+pub const DEFAULT_MEMBER_PREFIXES: &str = "~&@%+";
+
+/// The fields of an RPL_NAMREPLY (353) reply.
+#[derive(PartialEq, Debug)]
+pub struct NamesReply<'a> {
+    /// The channel visibility: `=` for public, `*` for private and `@` for secret channels
+    pub symbol: &'a str,
+    pub channel: &'a str,
+    pub members: Vec<NamesMember<'a>>
+}
+
+/// A channel member listed in a NAMES reply, e.g. `@+nick`.
+#[derive(PartialEq, Debug)]
+pub struct NamesMember<'a> {
+    /// All of the member's prefixes, which there can be several of with IRCv3 multi-prefix
+    pub prefixes: &'a str,
+    pub nick: &'a str
+}
+impl<'a> NamesMember<'a> {
+    /// Returns the highest ranked prefix, as servers list them in rank order.
+    pub fn highest_prefix(&self) -> Option<char> {
+        self.prefixes.chars().next()
+    }
+}
+
+/// Parses an RPL_NAMREPLY (353) reply with the default member prefixes.
+pub fn parse_names_reply<'a>(msg: &'a Message<'_>) -> Option<NamesReply<'a>> {
+    parse_names_reply_with_prefixes(msg, DEFAULT_MEMBER_PREFIXES)
+}
+
+/// Parses an RPL_NAMREPLY (353) reply, splitting any of `prefixes`, as advertised by ISUPPORT
+/// PREFIX, off the members. Returns `None` for other or malformed messages.
+pub fn parse_names_reply_with_prefixes<'a>(msg: &'a Message<'_>, prefixes: &str) -> Option<NamesReply<'a>> {
+    if msg.command != Command::Numeric(353) {
+        return None;
+    }
+    match *msg.numeric_args()? {
+        [ref symbol, ref channel, ref names] => Some(NamesReply {
+            symbol,
+            channel,
+            members: names.split(' ')
+                .filter(|name| !name.is_empty())
+                .map(|name| {
+                    let nick = name.trim_start_matches(|c| prefixes.contains(c));
+                    NamesMember { prefixes: &name[..name.len() - nick.len()], nick }
+                })
+                .collect()
+        }),
+        _ => None
+    }
+}
+
+/// Channel name prefixes from RFC 2812, used when the server doesn't advertise CHANTYPES.
+pub const DEFAULT_CHANTYPES: &str = "#&+!";
+
+/// Checks whether `target` is a channel using the default channel prefixes.
+pub fn is_channel(target: &str) -> bool {
+    is_channel_with_types(target, DEFAULT_CHANTYPES)
+}
+
+/// Checks whether `target` starts with one of `chantypes`, as advertised by ISUPPORT CHANTYPES.
+pub fn is_channel_with_types(target: &str, chantypes: &str) -> bool {
+    target.chars().next().is_some_and(|c| chantypes.contains(c))
+}
+
+/// Maximum nickname length from RFC 2812, used when the server doesn't advertise NICKLEN.
+pub const DEFAULT_NICKLEN: usize = 9;
+
+/// Checks `nick` against the RFC 2812 nickname grammar with the default length limit.
+pub fn is_valid_nick(nick: &str) -> bool {
+    is_valid_nick_with_len(nick, DEFAULT_NICKLEN)
+}
+
+/// Checks `nick` against the RFC 2812 nickname grammar, allowing up to `max_len` characters
+/// as advertised by ISUPPORT NICKLEN.
+pub fn is_valid_nick_with_len(nick: &str, max_len: usize) -> bool {
+    fn is_special(c: char) -> bool {
+        "[]\\`_^{|}".contains(c)
+    }
+    let mut chars = nick.chars();
+    match chars.next() {
+        Some(first) if first.is_ascii_alphabetic() || is_special(first) => {},
+        _ => return false
+    }
+    nick.len() <= max_len && chars.all(|c| c.is_ascii_alphanumeric() || is_special(c) || c == '-')
+}
+
+/// A single mode change from a MODE command, e.g. `+o nick`.
+#[derive(PartialEq, Debug)]
+pub struct ModeChange<'a> {
+    pub add: bool,
+    pub mode: char,
+    pub arg: Option<&'a str>
+}
+
+/// Pairs the flags of a mode string with their arguments. `params` starts with the mode
+/// string, e.g. `["+o-v", "nick1", "nick2"]`, and `arg_modes` lists the mode characters that
+/// take an argument, which depends on the ISUPPORT CHANMODES and PREFIX tokens.
+pub fn parse_mode_params<'a, S: AsRef<str>>(params: &'a [S], arg_modes: &str) -> Vec<ModeChange<'a>> {
+    parse_mode_changes(params, arg_modes, "")
+}
+// Like parse_mode_params, but the modes in `set_arg_modes` only have an argument when they're set
+fn parse_mode_changes<'a, S: AsRef<str>>(params: &'a [S], arg_modes: &str, set_arg_modes: &str) -> Vec<ModeChange<'a>> {
+    let mut ret = Vec::new();
+    let (modes, args) = match params.split_first() {
+        Some((modes, args)) => (modes.as_ref(), args),
+        None => return ret
+    };
+    let mut args = args.iter().map(|arg| arg.as_ref());
+    let mut add = true;
+    for mode in modes.chars() {
+        match mode {
+            '+' => add = true,
+            '-' => add = false,
+            _ => {
+                let has_arg = arg_modes.contains(mode) || add && set_arg_modes.contains(mode);
+                let arg = if has_arg { args.next() } else { None };
+                ret.push(ModeChange { add, mode, arg });
+            }
+        }
+    }
+    ret
+}
+
+/// The channel modes from RFC 2812 that have an argument when they're set, the key and the
+/// user limit, used when the server doesn't advertise CHANMODES.
+pub const DEFAULT_SET_ARG_MODES: &str = "kl";
+
+/// Parses the modes of an RPL_CHANNELMODEIS (324) reply with the default argument modes.
+pub fn parse_channel_mode_reply<'a>(msg: &'a Message<'_>) -> Option<(&'a str, Vec<ModeChange<'a>>)> {
+    parse_channel_mode_reply_with_arg_modes(msg, DEFAULT_SET_ARG_MODES)
+}
+
+/// Returns the channel of an RPL_CHANNELMODEIS (324) reply and its current modes, paired with
+/// their arguments like `parse_mode_params` does for MODE. Returns `None` for other messages.
+pub fn parse_channel_mode_reply_with_arg_modes<'a>(msg: &'a Message<'_>, arg_modes: &str) -> Option<(&'a str, Vec<ModeChange<'a>>)> {
+    if msg.command != Command::Numeric(324) {
+        return None;
+    }
+    match *msg.numeric_args()? {
+        [ref channel, ref modes @ ..] if !modes.is_empty() => Some((channel, parse_mode_params(modes, arg_modes))),
+        _ => None
+    }
+}
+
+/// Holds what a server advertised in its RPL_ISUPPORT (005) replies, for the helpers whose
+/// results depend on it. Until a reply is applied, it uses the same defaults as the free
+/// functions, and RFC 2812 `beIkl` for the channel modes.
+#[derive(PartialEq, Debug, Clone)]
+pub struct Parser {
+    chantypes: String,
+    member_prefixes: String,
+    prefix_modes: String,
+    // The CHANMODES that have an argument when they're set, and those that always have one
+    set_arg_chanmodes: String,
+    arg_chanmodes: String,
+    // All modes that always have an argument in MODE, the prefix modes included
+    arg_modes: String,
+    nicklen: usize,
+    casemapping: CaseMapping
+}
+impl Default for Parser {
+    fn default() -> Parser {
+        Parser {
+            chantypes: DEFAULT_CHANTYPES.to_string(),
+            member_prefixes: DEFAULT_MEMBER_PREFIXES.to_string(),
+            prefix_modes: "qaohv".to_string(),
+            set_arg_chanmodes: DEFAULT_SET_ARG_MODES.to_string(),
+            arg_chanmodes: "beIk".to_string(),
+            arg_modes: "qaohvbeIk".to_string(),
+            nicklen: DEFAULT_NICKLEN,
+            casemapping: CaseMapping::default()
+        }
+    }
+}
+impl Parser {
+    pub fn new() -> Parser {
+        Parser::default()
+    }
+    /// Parses a message like `parse_message`.
+    pub fn parse<'a>(&self, input: &'a str) -> Result<Message<'a>, ParserError> {
+        parse_message(input)
+    }
+    /// Updates the context from the CHANTYPES, PREFIX, CHANMODES, NICKLEN and CASEMAPPING tokens
+    /// of an RPL_ISUPPORT (005) reply. Other messages and tokens, as well as malformed or
+    /// unknown values, are ignored.
+    pub fn apply_isupport(&mut self, msg: &Message<'_>) {
+        for (key, value) in parse_isupport(msg) {
+            match (key, value.unwrap_or("")) {
+                ("CHANTYPES", value) => self.chantypes = value.to_string(),
+                ("PREFIX", "") => {
+                    self.prefix_modes.clear();
+                    self.member_prefixes.clear();
+                }
+                ("PREFIX", value) => {
+                    if let Some(pairs) = parse_prefix_token(value) {
+                        self.prefix_modes = pairs.iter().map(|&(mode, _)| mode).collect();
+                        self.member_prefixes = pairs.iter().map(|&(_, sigil)| sigil).collect();
+                    }
+                }
+                ("CHANMODES", value) => {
+                    let types: Vec<&str> = value.split(',').collect();
+                    if types.len() >= 4 {
+                        self.arg_chanmodes = format!("{}{}", types[0], types[1]);
+                        self.set_arg_chanmodes = format!("{}{}", types[1], types[2]);
+                    }
+                }
+                ("NICKLEN", value) => {
+                    if let Ok(nicklen) = value.parse() {
+                        self.nicklen = nicklen;
+                    }
+                }
+                ("CASEMAPPING", value) => {
+                    if let Some(casemapping) = CaseMapping::from_token(value) {
+                        self.casemapping = casemapping;
+                    }
+                }
+                _ => {}
+            }
+        }
+        self.arg_modes = format!("{}{}", self.prefix_modes, self.arg_chanmodes);
+    }
+    pub fn chantypes(&self) -> &str {
+        &self.chantypes
+    }
+    /// Returns the channel membership prefixes in rank order, e.g. `@+`.
+    pub fn member_prefixes(&self) -> &str {
+        &self.member_prefixes
+    }
+    pub fn nicklen(&self) -> usize {
+        self.nicklen
+    }
+    pub fn casemapping(&self) -> CaseMapping {
+        self.casemapping
+    }
+    pub fn is_channel(&self, target: &str) -> bool {
+        is_channel_with_types(target, &self.chantypes)
+    }
+    pub fn is_valid_nick(&self, nick: &str) -> bool {
+        is_valid_nick_with_len(nick, self.nicklen)
+    }
+    /// Compares nicks or channel names under the server's casemapping.
+    pub fn irc_eq(&self, a: &str, b: &str) -> bool {
+        irc_eq(a, b, self.casemapping)
+    }
+    /// Returns the channels a message is about, see `Message::channels`.
+    pub fn channels<'m>(&self, msg: &'m Message<'_>) -> Vec<&'m str> {
+        msg.channels_with_types(&self.chantypes)
+    }
+    /// Pairs the flags of a mode string with their arguments, see `parse_mode_params`. Unlike it,
+    /// this knows which modes only have an argument when they're set, like `l`.
+    pub fn parse_mode_params<'m, S: AsRef<str>>(&self, params: &'m [S]) -> Vec<ModeChange<'m>> {
+        parse_mode_changes(params, &self.arg_modes, &self.set_arg_chanmodes)
+    }
+    pub fn parse_names_reply<'m>(&self, msg: &'m Message<'_>) -> Option<NamesReply<'m>> {
+        parse_names_reply_with_prefixes(msg, &self.member_prefixes)
+    }
+    pub fn parse_channel_mode_reply<'m>(&self, msg: &'m Message<'_>) -> Option<(&'m str, Vec<ModeChange<'m>>)> {
+        parse_channel_mode_reply_with_arg_modes(msg, &self.set_arg_chanmodes)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    #[test]
+    fn test_parsing_host() {
+        match super::host_parser(b"user!host@example.com ") {
+            Ok((_, (nick, user, host))) => {
+                assert_eq!(nick, "user");
+                assert_eq!(user, Some("host"));
+                assert_eq!(host, Some("example.com"));
+            },
+            Err(nom::Err::Incomplete(i)) => panic!("Incomplete: {:?}", i),
+            _ => panic!("Error while parsing host")
+        }
+    }
+    #[test]
+    fn test_parsing_line() {
+        match super::message_parser(b"NOTICE AUTH :*** Looking up your hostname\r") {
+            Ok((_, msg)) => {
+                assert_eq!(msg.prefix, None);
+                assert_eq!(msg.command, Command::Named("NOTICE".into()));
+                assert_eq!(msg.params, vec!["AUTH", "*** Looking up your hostname"]);
+            },
+            Err(nom::Err::Incomplete(i)) => panic!("Incomplete: {:?}", i),
+            _ => panic!("Error while parsing auth message")
+        }
+    }
+    #[test]
+    fn test_parsing_line_without_trailing() {
+        match super::message_parser(b":port80a.se.quakenet.org 004 RustBot port80a.se.quakenet.org u2.10.12.10+snircd(1.3.4a) dioswkgxRXInP biklmnopstvrDcCNuMT bklov\r\n") {
+            Ok((_, msg)) => {
+                assert_eq!(msg.prefix, Some(Prefix::Server("port80a.se.quakenet.org")));
+                assert_eq!(msg.command, Command::Numeric(4));
+                assert_eq!(msg.params, vec!["RustBot", "port80a.se.quakenet.org", "u2.10.12.10+snircd(1.3.4a)", "dioswkgxRXInP", "biklmnopstvrDcCNuMT", "bklov"]);
+            },
+            Err(nom::Err::Incomplete(i)) => panic!("Incomplete: {:?}", i),
+            _ => panic!("Error while parsing a message without trailing stuff")
+        }
+    }
+    #[test]
+    fn test_parsing_prefix() {
+        match super::prefix_parser(b":this.represents.a.server.prefix ") {
+            Ok((left, Prefix::Server(server))) => {
+                assert_eq!(server, "this.represents.a.server.prefix");
+                assert_eq!(left.len(), 0);
+            },
+            Err(nom::Err::Incomplete(i)) => panic!("Incomplete: {:?}", i),
+            _ => panic!("Error while parsing prefix")
+        }
+    }
+    #[test]
+    fn test_parsing_user_prefixes() {
+        let cases = vec![
+            (&b":nick "[..], Prefix::User { nick: "nick", user: None, host: None }),
+            (&b":nick@host.example.com "[..], Prefix::User { nick: "nick", user: None, host: Some("host.example.com") }),
+            (&b":nick!user "[..], Prefix::User { nick: "nick", user: Some("user"), host: None }),
+            (&b":nick!user@host.example.com "[..], Prefix::User { nick: "nick", user: Some("user"), host: Some("host.example.com") }),
+            (&b":nick!~user@host.example.com "[..], Prefix::User { nick: "nick", user: Some("~user"), host: Some("host.example.com") }),
+            (&b":nick!~user "[..], Prefix::User { nick: "nick", user: Some("~user"), host: None }),
+            (&b":irc.example.com "[..], Prefix::Server("irc.example.com"))
+        ];
+        for (input, expected) in cases {
+            match super::prefix_parser(input) {
+                Ok((left, prefix)) => {
+                    assert_eq!(prefix, expected);
+                    assert_eq!(left.len(), 0);
+                },
+                Err(nom::Err::Incomplete(i)) => panic!("Incomplete: {:?}", i),
+                _ => panic!("Error while parsing prefix {:?}", input)
+            }
+        }
+    }
+    #[test]
+    fn test_parsing_message_using_parse_message() {
+        let msg = "NOTICE AUTH :*** Looking up your hostname\r\nNOTICE AUTH :*** Checking Ident\r\nNOTICE AUTH :*** Found your hostname\r\n";
+        let msgs = parse_messages(msg);
+        assert_eq!(msgs.len(), 3);
+        for m in msgs {
+            assert_eq!(m.unwrap().command, "NOTICE");
+        }
+    }
+    #[test]
+    fn test_inline_host() {
+        parse_message(":server.example.com 333 RustBot #channel user!host@example.com 123456789\r\n").unwrap();
+    }
+    #[test]
+    fn test_parsing_tags() {
+        let msg = parse_message("@time=2021-01-01T00:00:00.000Z;account=bob;example.com/foo;+draft/reply=123 :nick!user@host PRIVMSG #chan :hi\r\n").unwrap();
+        assert_eq!(msg.tags, vec![
+            ("time", Some("2021-01-01T00:00:00.000Z")),
+            ("account", Some("bob")),
+            ("example.com/foo", None),
+            ("+draft/reply", Some("123"))
+        ]);
+        assert_eq!(msg.prefix, Some(Prefix::User { nick: "nick", user: Some("user"), host: Some("host") }));
+        assert_eq!(msg.command, Command::Named("PRIVMSG".into()));
+        assert_eq!(msg.params, vec!["#chan", "hi"]);
+    }
+    #[test]
+    fn test_parsing_without_tags() {
+        let msg = parse_message(":nick!user@host PRIVMSG #chan :hi\r\n").unwrap();
+        assert!(msg.tags.is_empty());
+    }
+    #[test]
+    fn test_tags_stop_at_line_end() {
+        let input = "@a\r\nPING x\r\nNEXT\r\n";
+        assert_eq!(parse_message_with_rest(input).unwrap_err(), ParserError::MissingCommand { position: 2 });
+        let mut msg = Message::default();
+        assert_eq!(parse_into(&mut msg, input).unwrap_err(), ParserError::MissingCommand { position: 2 });
+        let msgs = parse_messages(input);
+        assert_eq!(msgs.len(), 3);
+        assert_eq!(msgs[0], Err(ParserError::MissingCommand { position: 2 }));
+        assert_eq!(msgs[1].as_ref().unwrap().params, vec!["x"]);
+        assert_eq!(msgs[2].as_ref().unwrap().command, "NEXT");
+    }
+    #[test]
+    fn test_unescaping_tag_values() {
+        assert!(matches!(unescape_tag_value("plain"), Cow::Borrowed("plain")));
+        assert_eq!(unescape_tag_value("a\\:b\\sc\\\\d\\r\\n"), "a;b c\\d\r\n");
+        assert_eq!(unescape_tag_value("\\x"), "x");
+        assert_eq!(unescape_tag_value("trailing\\"), "trailing");
+        assert_eq!(unescape_tag_value("\\"), "");
+    }
+    #[test]
+    fn test_tag_accessors() {
+        let msg = parse_message("@account=bob;note=hello\\sworld;flag PRIVMSG #chan :hi\r\n").unwrap();
+        assert_eq!(msg.raw_tag("note"), Some("hello\\sworld"));
+        assert_eq!(msg.tag_value("note"), Some("hello world".into()));
+        assert_eq!(msg.tag_value("account"), Some("bob".into()));
+        assert_eq!(msg.tag_value("flag"), None);
+        assert_eq!(msg.tag_value("missing"), None);
+    }
+    #[test]
+    fn test_tag_lookup() {
+        let msg = parse_message("@+example.com/reply=42;example.com/flag;empty=;account=bob PRIVMSG #chan :hi\r\n")
+            .unwrap();
+        assert_eq!(msg.tag("+example.com/reply"), Some(Some("42")));
+        assert_eq!(msg.tag("example.com/reply"), None);
+        assert_eq!(msg.tag("example.com/flag"), Some(None));
+        assert_eq!(msg.tag("empty"), Some(None));
+        assert_eq!(msg.tag("account"), Some(Some("bob")));
+        assert!(msg.has_tag("example.com/flag"));
+        assert!(!msg.has_tag("+example.com/flag"));
+        assert!(!msg.has_tag("missing"));
+        // IRCv3 treats an empty value the same as a missing one, whichever lookup is used
+        let msg = parse_message("@k= PING\r\n").unwrap();
+        assert_eq!(msg.tag("k"), Some(None));
+        assert_eq!(msg.raw_tag("k"), None);
+        assert_eq!(msg.tag_value("k"), None);
+        assert!(msg.has_tag("k"));
+    }
+    #[test]
+    fn test_owned_message_outlives_input() {
+        let owned = {
+            let input = String::from("@account=bob :nick!user@host PRIVMSG #chan :hello there\r\n");
+            OwnedMessage::from(parse_message(&input).unwrap())
+        };
+        assert_eq!(owned.tags, vec![("account".to_string(), Some("bob".to_string()))]);
+        assert_eq!(owned.prefix, Some(OwnedPrefix::User { nick: "nick".into(), user: Some("user".into()), host: Some("host".into()) }));
+        assert_eq!(owned.command, OwnedCommand::Named("PRIVMSG".into()));
+        assert_eq!(owned.params, vec!["#chan", "hello there"]);
+    }
+    #[test]
+    fn test_parsing_multiple_messages() {
+        let msgs = parse_messages("NOTICE AUTH :*** Looking up your hostname\r\n\r\nNOTICE AUTH :*** Checking Ident\r\n: BROKEN\r\nPING :partial");
+        assert_eq!(msgs.len(), 4);
+        assert_eq!(msgs[0].as_ref().unwrap().params, vec!["AUTH", "*** Looking up your hostname"]);
+        assert_eq!(msgs[1].as_ref().unwrap().params, vec!["AUTH", "*** Checking Ident"]);
+        assert!(msgs[2].is_err());
+        assert!(msgs[3].is_err());
+    }
+    #[test]
+    fn test_message_stream_buffers_partial_lines() {
+        let mut stream = MessageStream::new();
+        stream.push(b"PING :irc.example");
+        assert_eq!(stream.next_message(), None);
+        stream.push(b".com\r\nNOTICE AUTH :*** Checking Ident\r\n\r\nPRIVMSG #chan");
+        assert_eq!(stream.next_message().unwrap().params, vec!["irc.example.com"]);
+        assert_eq!(stream.next_message().unwrap().params, vec!["AUTH", "*** Checking Ident"]);
+        assert_eq!(stream.next_message(), None);
+        stream.push(b" :hi there\r\n");
+        assert_eq!(stream.next_message().unwrap().params, vec!["#chan", "hi there"]);
+        assert_eq!(stream.next_message(), None);
+    }
+    #[test]
+    fn test_message_stream_drops_oversized_partial_lines() {
+        let mut stream = MessageStream::new();
+        stream.push(b"PING :a\r\nPRIVMSG #chan :");
+        for _ in 0..100 {
+            stream.push(&[b'x'; 1024]);
+        }
+        assert!(stream.buffer.len() <= MAX_TAGS_LENGTH + MAX_LINE_LENGTH);
+        stream.push(b"xxx\r\nPING :b\r\n");
+        assert_eq!(stream.next_message().unwrap().params, vec!["a"]);
+        assert_eq!(stream.next_message().unwrap().params, vec!["b"]);
+        assert_eq!(stream.next_message(), None);
+        let mut stream = MessageStream::new();
+        let line = format!("PRIVMSG #chan :{}\r\n", "x".repeat(MAX_TAGS_LENGTH + MAX_LINE_LENGTH - 15));
+        stream.push(line.as_bytes());
+        assert_eq!(stream.next_message(), None);
+        assert!(stream.buffer.is_empty());
+    }
+    #[test]
+    fn test_numeric_names() {
+        assert_eq!(Command::Numeric(1).numeric_name(), Some("RPL_WELCOME"));
+        assert_eq!(Command::Numeric(353).numeric_name(), Some("RPL_NAMREPLY"));
+        assert_eq!(Command::Numeric(433).numeric_name(), Some("ERR_NICKNAMEINUSE"));
+        assert_eq!(Command::Numeric(999).numeric_name(), None);
+        assert_eq!(Command::Named("PRIVMSG".into()).numeric_name(), None);
+        let msg = parse_message(":irc.example.com 376 RustBot :End of /MOTD command.\r\n").unwrap();
+        assert_eq!(msg.command.numeric_name(), Some("RPL_ENDOFMOTD"));
+    }
+    #[test]
+    fn test_matches_name() {
+        for name in &["001", "RPL_WELCOME", "rpl_welcome"] {
+            assert!(Command::Numeric(1).matches_name(name), "{}", name);
+        }
+        for name in &["002", "", "1", "01", "0001", "+1", "RPL_YOURHOST", "WELCOME"] {
+            assert!(!Command::Numeric(1).matches_name(name), "{}", name);
+        }
+        assert!(!Command::Numeric(999).matches_name("RPL_WELCOME"));
+        assert!(Command::Named("PRIVMSG".into()).matches_name("privmsg"));
+        assert!(!Command::Named("PRIVMSG".into()).matches_name("001"));
+    }
+    #[test]
+    fn test_display_round_trip() {
+        let lines = vec![
+            "PRIVMSG #chan :hello world\r\n",
+            ":nick!user@host PRIVMSG #chan :hello world\r\n",
+            "NOTICE AUTH :*** Looking up your hostname\r\n",
+            ":irc.example.com MODE #chan +o nick\r\n",
+            "@account=bob;+draft/typing :nick!user@host JOIN #chan\r\n",
+            "PING :irc.example.com\r\n",
+            "PRIVMSG #chan :ok\r\n",
+            "PRIVMSG #chan ok\r\n"
+        ];
+        for line in lines {
+            assert_eq!(parse_message(line).unwrap().to_string(), line);
+        }
+    }
+    #[test]
+    fn test_tracking_trailing_param() {
+        let msg = parse_message("PRIVMSG #chan :ok\r\n").unwrap();
+        assert!(msg.has_trailing);
+        assert_eq!(msg.params, vec!["#chan", "ok"]);
+        let msg = parse_message("PRIVMSG #chan ok\r\n").unwrap();
+        assert!(!msg.has_trailing);
+        assert_eq!(msg.params, vec!["#chan", "ok"]);
+    }
+    #[test]
+    fn test_rejecting_long_lines() {
+        let line = format!("PRIVMSG #chan :{}\r\n", "a".repeat(600 - 17));
+        assert_eq!(line.len(), 600);
+        assert_eq!(parse_message(&line).unwrap_err(), ParserError::LineTooLong { len: 600 });
+        let line = format!("PRIVMSG #chan :{}\r\n", "a".repeat(MAX_LINE_LENGTH - 17));
+        assert!(parse_message(&line).is_ok());
+    }
+    #[test]
+    fn test_rejecting_long_tags() {
+        let tags = format!("@a={} ", "x".repeat(MAX_TAGS_LENGTH - 4));
+        assert_eq!(tags.len(), MAX_TAGS_LENGTH);
+        let text = "a".repeat(MAX_LINE_LENGTH - 17);
+        let line = format!("{}PRIVMSG #chan :{}\r\n", tags, text);
+        assert_eq!(parse_message(&line).unwrap().params[1], text);
+        let line = format!("@a={} PRIVMSG #chan :hi\r\n", "x".repeat(MAX_TAGS_LENGTH));
+        assert_eq!(parse_message(&line).unwrap_err(), ParserError::TagsTooLong { len: MAX_TAGS_LENGTH + 4 });
+        let line = format!("@a=b PRIVMSG #chan :{}\r\n", "a".repeat(600 - 17));
+        assert_eq!(parse_message(&line).unwrap_err(), ParserError::LineTooLong { len: 600 });
+    }
+    #[test]
+    fn test_rejecting_long_lines_without_terminator() {
+        let line = format!("PRIVMSG #chan :{}", "a".repeat(MAX_LINE_LENGTH - 2 - 15));
+        assert_eq!(line.len(), MAX_LINE_LENGTH - 2);
+        assert!(parse_message_line(&line).is_ok());
+        assert!(parse_message(&format!("{}\r\n", line)).is_ok());
+        let line = format!("{}a", line);
+        assert_eq!(parse_message_line(&line).unwrap_err(), ParserError::LineTooLong { len: MAX_LINE_LENGTH + 1 });
+        assert_eq!(parse_message(&format!("{}\r\n", line)).unwrap_err(), ParserError::LineTooLong { len: MAX_LINE_LENGTH + 1 });
+        let line = format!("@a=b {}", line);
+        assert_eq!(parse_message_line(&line).unwrap_err(), ParserError::LineTooLong { len: MAX_LINE_LENGTH + 1 });
+        assert_eq!(parse_message_line("@a=b").unwrap_err(), ParserError::MissingCommand { position: 4 });
+    }
+    #[test]
+    fn test_structured_errors() {
+        assert_eq!(parse_message(": PRIVMSG #chan :hi\r\n").unwrap_err(), ParserError::MalformedPrefix { position: 0 });
+        assert_eq!(parse_message(":nick!@host PRIVMSG #chan :hi\r\n").unwrap_err(), ParserError::MalformedPrefix { position: 0 });
+        assert_eq!(parse_message(":nick!user@host \r\n").unwrap_err(), ParserError::MissingCommand { position: 16 });
+        assert_eq!(parse_message(":prefix\r\n").unwrap_err(), ParserError::MissingCommand { position: 7 });
+        assert_eq!(parse_message(":irc.example.com\r\n").unwrap_err(), ParserError::MissingCommand { position: 16 });
+        assert_eq!(parse_message("\r\n").unwrap_err(), ParserError::MissingCommand { position: 0 });
+        assert_eq!(parse_message("@a=b :nick! PRIVMSG\r\n").unwrap_err(), ParserError::MalformedPrefix { position: 5 });
+        assert_eq!(parse_message("@a\r\n").unwrap_err(), ParserError::MissingCommand { position: 2 });
+        assert_eq!(parse_message(":nick!user@host PRIV2MSG #chan :hi\r\n").unwrap_err(), ParserError::MalformedCommand { position: 16 });
+        assert_eq!(parse_message("2PRIVMSG #chan :hi\r\n").unwrap_err(), ParserError::MalformedCommand { position: 0 });
+        assert_eq!(parse_message("PRIV\x07MSG #chan :hi\r\n").unwrap_err(), ParserError::MalformedCommand { position: 0 });
+        assert_eq!(parse_message("PRÏVMSG #chan :hi\r\n").unwrap_err(), ParserError::MalformedCommand { position: 0 });
+        assert_eq!(ParserError::MalformedCommand { position: 3 }.position(), Some(3));
+        assert_eq!(parse_message("@a=b :nick! PRIVMSG\r\n").unwrap_err().position(), Some(5));
+        assert_eq!(ParserError::Incomplete.position(), None);
+        assert_eq!(parse_message(":prefix").unwrap_err(), ParserError::Incomplete);
+        assert_eq!(parse_message("").unwrap_err(), ParserError::Incomplete);
+        assert_eq!(parse_message_bytes(b"PRIV\xffMSG #chan :hi\r\n").unwrap_err(), ParserError::InvalidUtf8 { position: 4 });
+    }
+    #[test]
+    fn test_parsing_bytes_with_invalid_utf8_params() {
+        let msg = parse_message_bytes(b":nick!user@host PRIVMSG #k\xe4\xe4k :caf\xe9 au lait\r\n").unwrap();
+        assert_eq!(msg.prefix, Some(Prefix::User { nick: "nick", user: Some("user"), host: Some("host") }));
+        assert_eq!(msg.params, vec!["#k\u{fffd}\u{fffd}k", "caf\u{fffd} au lait"]);
+        let msg = parse_message_bytes(b"PRIVMSG #chan :hello\r\n").unwrap();
+        assert!(msg.params.iter().all(|p| matches!(*p, Cow::Borrowed(_))));
+    }
+    #[test]
+    fn test_building_messages() {
+        let msg = MessageBuilder::new().command("PRIVMSG").param("#chan").trailing("hello world").build().unwrap();
+        assert_eq!(msg.to_string(), "PRIVMSG #chan :hello world\r\n");
+        let msg = MessageBuilder::new().command("PONG").trailing("token").build().unwrap();
+        assert_eq!(msg.to_string(), "PONG :token\r\n");
+        let msg = MessageBuilder::new()
+            .prefix(Prefix::Server("irc.example.com"))
+            .numeric(401)
+            .param("RustBot")
+            .param("nobody")
+            .trailing("No such nick/channel")
+            .build().unwrap();
+        assert_eq!(msg.command, OwnedCommand::Numeric(401));
+        assert_eq!(msg.to_string(), ":irc.example.com 401 RustBot nobody :No such nick/channel\r\n");
+    }
+    #[test]
+    fn test_parsing_ctcp() {
+        let msg = parse_message(":nick!user@host PRIVMSG #chan :\x01ACTION waves\x01\r\n").unwrap();
+        assert_eq!(msg.ctcp(), Some(Ctcp { command: "ACTION", params: "waves" }));
+        let msg = parse_message(":nick!user@host PRIVMSG RustBot :\x01VERSION\x01\r\n").unwrap();
+        assert_eq!(msg.ctcp(), Some(Ctcp { command: "VERSION", params: "" }));
+        let msg = parse_message(":nick!user@host NOTICE RustBot :\x01ACTION waves back\r\n").unwrap();
+        assert_eq!(msg.ctcp(), Some(Ctcp { command: "ACTION", params: "waves back" }));
+        let msg = parse_message(":nick!user@host PRIVMSG #chan :hello\r\n").unwrap();
+        assert_eq!(msg.ctcp(), None);
+        let msg = parse_message(":nick!user@host TOPIC #chan :\x01ACTION waves\x01\r\n").unwrap();
+        assert_eq!(msg.ctcp(), None);
+    }
+    #[test]
+    fn test_matching_commands_ignoring_case() {
+        for line in &["privmsg #chan :hi\r\n", "PRIVMSG #chan :hi\r\n", "PrivMsg #chan :hi\r\n"] {
+            let msg = parse_message(line).unwrap();
+            assert!(msg.command.matches("PRIVMSG"));
+            assert!(msg.command.matches("privmsg"));
+            assert!(!msg.command.matches("NOTICE"));
+        }
+        assert!(!Command::Numeric(1).matches("PRIVMSG"));
+    }
+    #[test]
+    fn test_prefix_accessors() {
+        let msg = parse_message(":nick!~user@host.example.com PRIVMSG #chan :hi\r\n").unwrap();
+        let prefix = msg.prefix.unwrap();
+        assert_eq!(prefix.nick(), Some("nick"));
+        assert_eq!(prefix.user(), Some("~user"));
+        assert_eq!(prefix.host(), Some("host.example.com"));
+        let prefix = Prefix::User { nick: "nick", user: None, host: None };
+        assert_eq!((prefix.nick(), prefix.user(), prefix.host()), (Some("nick"), None, None));
+        let prefix = Prefix::Server("irc.example.com");
+        assert_eq!((prefix.nick(), prefix.user(), prefix.host()), (None, None, None));
+    }
+    #[test]
+    fn test_parsing_mode_changes() {
+        let msg = parse_message(":nick!user@host MODE #chan +o-v nick1 nick2\r\n").unwrap();
+        assert_eq!(parse_mode_params(&msg.params[1..], "ovbkl"), vec![
+            ModeChange { add: true, mode: 'o', arg: Some("nick1") },
+            ModeChange { add: false, mode: 'v', arg: Some("nick2") }
+        ]);
+        assert_eq!(parse_mode_params(&["+mnt"], "ovbkl"), vec![
+            ModeChange { add: true, mode: 'm', arg: None },
+            ModeChange { add: true, mode: 'n', arg: None },
+            ModeChange { add: true, mode: 't', arg: None }
+        ]);
+        assert_eq!(parse_mode_params(&["-m+k", "secret"], "k"), vec![
+            ModeChange { add: false, mode: 'm', arg: None },
+            ModeChange { add: true, mode: 'k', arg: Some("secret") }
+        ]);
+        assert!(parse_mode_params::<&str>(&[], "ov").is_empty());
+    }
+    #[cfg(feature = "serde")]
+    #[test]
+    fn test_serde_round_trip() {
+        extern crate serde_json;
+        let msg = parse_message(":nick!user@host PRIVMSG #chan :hello world\r\n").unwrap().to_owned();
+        let json = serde_json::to_string(&msg).unwrap();
+        assert!(json.contains(r#""command":{"type":"Named","value":"PRIVMSG"}"#));
+        assert_eq!(serde_json::from_str::<OwnedMessage>(&json).unwrap(), msg);
+        let numeric = serde_json::to_string(&OwnedCommand::Numeric(433)).unwrap();
+        assert_eq!(numeric, r#"{"type":"Numeric","value":433}"#);
+    }
+    #[test]
+    fn test_line_terminators() {
+        for line in &["PRIVMSG #chan :hello world\r\n", "PRIVMSG #chan :hello world\r", "PRIVMSG #chan :hello world\n"] {
+            let msg = parse_message(line).unwrap();
+            assert_eq!(msg.params, vec!["#chan", "hello world"]);
+        }
+        let msgs = parse_messages("PING :a\nPING :b\rPING :c\r\n");
+        assert_eq!(msgs.len(), 3);
+        assert!(msgs.iter().all(|msg| msg.is_ok()));
+        let mut stream = MessageStream::new();
+        stream.push(b"PING :a\nPING :b\r");
+        assert_eq!(stream.next_message().unwrap().params, vec!["a"]);
+        assert_eq!(stream.next_message(), None);
+        stream.push(b"\n");
+        assert_eq!(stream.next_message().unwrap().params, vec!["b"]);
+    }
+    #[test]
+    fn test_parsing_with_rest() {
+        let input = "NOTICE AUTH :*** Checking Ident\r\nPRIVMSG #chan :hi\r\nPING";
+        let (msg, rest) = parse_message_with_rest(input).unwrap();
+        assert_eq!(msg.params, vec!["AUTH", "*** Checking Ident"]);
+        assert_eq!(rest, "PRIVMSG #chan :hi\r\nPING");
+        let (msg, rest) = parse_message_with_rest(rest).unwrap();
+        assert_eq!(msg.params, vec!["#chan", "hi"]);
+        assert_eq!(rest, "PING");
+        assert_eq!(parse_message_with_rest(rest).unwrap_err(), ParserError::Incomplete);
+    }
+    #[test]
+    fn test_validating_nicks() {
+        assert!(is_valid_nick("RustBot"));
+        assert!(is_valid_nick("[away]"));
+        assert!(is_valid_nick("a-1_b|c"));
+        assert!(is_valid_nick("`^{}\\"));
+        assert!(!is_valid_nick(""));
+        assert!(!is_valid_nick("1nick"));
+        assert!(!is_valid_nick("-nick"));
+        assert!(!is_valid_nick("nick.name"));
+        assert!(!is_valid_nick("nick name"));
+        assert!(!is_valid_nick("TenLetters"));
+        assert!(is_valid_nick_with_len("TenLetters", 30));
+    }
+    #[test]
+    fn test_parsing_isupport() {
+        let msg = parse_message(":irc.example.com 005 RustBot PREFIX=(ov)@+ CHANTYPES=#& NICKLEN=30 WHOX :are supported by this server\r\n").unwrap();
+        assert_eq!(parse_isupport(&msg), vec![
+            ("PREFIX", Some("(ov)@+")),
+            ("CHANTYPES", Some("#&")),
+            ("NICKLEN", Some("30")),
+            ("WHOX", None)
+        ]);
+        let msg = parse_message(":irc.example.com 004 RustBot irc.example.com ircd-1.0 iow bklov\r\n").unwrap();
+        assert!(parse_isupport(&msg).is_empty());
+    }
+    #[test]
+    fn test_sender_nick() {
+        assert_eq!(parse_message(":nick!user@host PRIVMSG #chan :hi\r\n").unwrap().sender_nick(), Some("nick"));
+        assert_eq!(parse_message(":irc.example.com NOTICE * :hi\r\n").unwrap().sender_nick(), Some("irc.example.com"));
+        assert_eq!(parse_message("PING :irc.example.com\r\n").unwrap().sender_nick(), None);
+    }
+    #[test]
+    fn test_privmsg_helpers() {
+        let msg = parse_message(":nick!user@host privmsg #chan :hello world\r\n").unwrap();
+        assert_eq!(msg.privmsg_target(), Some("#chan"));
+        assert_eq!(msg.privmsg_text(), Some("hello world"));
+        let msg = parse_message(":nick!user@host PRIVMSG #chan\r\n").unwrap();
+        assert_eq!(msg.privmsg_target(), None);
+        assert_eq!(msg.privmsg_text(), None);
+        let msg = parse_message(":nick!user@host NOTICE #chan :hello world\r\n").unwrap();
+        assert_eq!(msg.privmsg_target(), None);
+        assert_eq!(msg.privmsg_text(), None);
+    }
+    #[test]
+    fn test_classifying_channels() {
+        for channel in &["#chan", "&local", "+modeless", "!12345chan"] {
+            assert!(is_channel(channel));
+        }
+        assert!(!is_channel("RustBot"));
+        assert!(!is_channel(""));
+        assert!(is_channel_with_types("#chan", "#"));
+        assert!(!is_channel_with_types("&local", "#"));
+        assert!(is_channel_with_types("~special", "#~"));
+    }
+    #[test]
+    fn test_preserving_empty_trailing_param() {
+        let msg = parse_message("PRIVMSG #chan :\r\n").unwrap();
+        assert_eq!(msg.params, vec!["#chan", ""]);
+        assert!(msg.has_trailing);
+        assert_eq!(msg.to_string(), "PRIVMSG #chan :\r\n");
+        let msg = parse_message("@+typing=active TAGMSG #chan :\r\n").unwrap();
+        assert_eq!(msg.params, vec!["#chan", ""]);
+    }
+    #[test]
+    fn test_empty_trailing_param_at_line_end() {
+        // The colon right before the terminator, however the line is terminated or fed in
+        let lines = ["PRIVMSG #chan :\r\n", "PRIVMSG #chan :\n", "PRIVMSG #chan :\r", "PRIVMSG #chan   :\r\n"];
+        for line in &lines {
+            let msg = parse_message(line).unwrap();
+            assert_eq!(msg.params.len(), 2, "{:?}", line);
+            assert_eq!(msg.trailing(), Some(""), "{:?}", line);
+        }
+        assert_eq!(parse_message_bytes(b"PRIVMSG #chan :\r\n").unwrap().params, vec!["#chan", ""]);
+        assert_eq!(parse_message_line("PRIVMSG #chan :").unwrap().params, vec!["#chan", ""]);
+        let mut msg = Message::default();
+        parse_into(&mut msg, "PRIVMSG #chan :\r\n").unwrap();
+        assert_eq!(msg.params, vec!["#chan", ""]);
+        let msg = parse_message("PING :\r\n").unwrap();
+        assert_eq!(msg.params, vec![""]);
+        assert!(msg.has_trailing);
+        assert_eq!(parse_message("PRIVMSG #chan :\r\nPING :x\r\n").unwrap().params, vec!["#chan", ""]);
+    }
+    #[test]
+    fn test_parsing_trailing_verbatim() {
+        let msg = parse_message(":nick!user@host PRIVMSG #chan :  hello   world :) \r\n").unwrap();
+        assert_eq!(msg.params, vec!["#chan", "  hello   world :) "]);
+        let msg = parse_message(":nick!user@host MODE #chan +b *!*@2001:db8::1\r\n").unwrap();
+        assert_eq!(msg.params, vec!["#chan", "+b", "*!*@2001:db8::1"]);
+        assert!(!msg.has_trailing);
+    }
+    #[test]
+    fn test_join_channels() {
+        let msg = parse_message("JOIN #a,#b,#c key1,key2\r\n").unwrap();
+        assert_eq!(msg.join_channels(), Some(vec![("#a", Some("key1")), ("#b", Some("key2")), ("#c", None)]));
+        let msg = parse_message(":nick!user@host JOIN #a\r\n").unwrap();
+        assert_eq!(msg.join_channels(), Some(vec![("#a", None)]));
+        let msg = parse_message("JOIN #a key1,key2\r\n").unwrap();
+        assert_eq!(msg.join_channels(), Some(vec![("#a", Some("key1"))]));
+        let msg = parse_message("JOIN 0\r\n").unwrap();
+        assert_eq!(msg.join_channels(), Some(vec![("0", None)]));
+        assert_eq!(parse_message("JOIN\r\n").unwrap().join_channels(), None);
+        assert_eq!(parse_message("PART #a\r\n").unwrap().join_channels(), None);
+    }
+    #[test]
+    fn test_numeric_commands() {
+        assert_eq!(parse_message(":server 001 nick :Welcome\r\n").unwrap().command, Command::Numeric(1));
+        assert_eq!(parse_message(":server 999 nick\r\n").unwrap().command, Command::Numeric(999));
+        assert_eq!(parse_message("12 nick\r\n").unwrap_err(), ParserError::MalformedCommand { position: 0 });
+        assert_eq!(parse_message("1234 nick\r\n").unwrap_err(), ParserError::MalformedCommand { position: 0 });
+        assert_eq!(parse_message("+12 nick\r\n").unwrap_err(), ParserError::MalformedCommand { position: 0 });
+        assert_eq!(Command::numeric(433), Some(Command::Numeric(433)));
+        assert_eq!(Command::numeric(999), Some(Command::Numeric(999)));
+        assert_eq!(Command::numeric(1000), None);
+    }
+    #[cfg(feature = "std")]
+    struct ChunkedReader {
+        chunks: Vec<&'static [u8]>
+    }
+    #[cfg(feature = "std")]
+    impl std::io::Read for ChunkedReader {
+        fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+            if self.chunks.is_empty() {
+                return Ok(0);
+            }
+            let chunk = self.chunks.remove(0);
+            buf[..chunk.len()].copy_from_slice(chunk);
+            Ok(chunk.len())
+        }
+    }
+
+    #[test]
+    #[cfg(feature = "std")]
+    fn test_message_reader_reports_oversized_lines() {
+        let input = format!("PRIVMSG #chan :{}\r\nPING :a\r\n", "x".repeat(20000));
+        let msgs: Vec<_> = MessageReader::new(std::io::Cursor::new(input)).collect();
+        assert_eq!(msgs.len(), 2);
+        assert!(matches!(msgs[0], Err(ParserError::LineTooLong { len }) if len > MAX_TAGS_LENGTH + MAX_LINE_LENGTH));
+        assert_eq!(msgs[1].as_ref().unwrap().params, vec!["a"]);
+    }
+    #[test]
+    #[cfg(feature = "std")]
+    fn test_message_reader() {
+        let reader = ChunkedReader { chunks: vec![b"PING :a\r\nPING :b\r\nPRIV", b"MSG #chan :hi\r", b"\n\r\nPING :c\r"] };
+        let msgs: Vec<_> = MessageReader::new(reader).collect();
+        assert_eq!(msgs.len(), 4);
+        assert_eq!(msgs[0].as_ref().unwrap().params, vec!["a"]);
+        assert_eq!(msgs[1].as_ref().unwrap().params, vec!["b"]);
+        assert_eq!(msgs[2].as_ref().unwrap().params, vec!["#chan", "hi"]);
+        assert_eq!(msgs[3].as_ref().unwrap().params, vec!["c"]);
+
+        let reader = ChunkedReader { chunks: vec![b": BROKEN\r\nPING :a\r\nPING :unterminated"] };
+        let msgs: Vec<_> = MessageReader::new(reader).collect();
+        assert_eq!(msgs.len(), 3);
+        assert_eq!(msgs[0], Err(ParserError::MalformedPrefix { position: 0 }));
+        assert!(msgs[1].is_ok());
+        assert_eq!(msgs[2], Err(ParserError::Incomplete));
+    }
+    #[test]
+    fn test_raw_line() {
+        let line = "@time=now :nick!user@host PRIVMSG #chan  :Hello  there";
+        let input = format!("{}\r\nPING :x\r\n", line);
+        let (msg, _) = parse_message_with_rest(&input).unwrap();
+        assert_eq!(msg.raw, line);
+        assert!(match msg.raw { Cow::Borrowed(raw) => raw.as_ptr() == input.as_ptr(), Cow::Owned(_) => false });
+        assert_eq!(parse_message("PING :x\n").unwrap().raw, "PING :x");
+        assert_eq!(parse_message_bytes(b"PRIVMSG #chan :caf\xe9\r\n").unwrap().raw, "PRIVMSG #chan :caf\u{fffd}");
+    }
+    #[test]
+    fn test_parser_context() {
+        let mut parser = Parser::new();
+        assert!(parser.is_channel("&local"));
+        assert!(!parser.is_valid_nick("a_long_nickname"));
+        assert!(parser.irc_eq("nick[]", "NICK{}"));
+        let isupport = parser.parse(":irc.example.com 005 me CHANTYPES=# PREFIX=(ov)@+ CHANMODES=beI,k,lj,imnpst NICKLEN=30 \
+                                     CASEMAPPING=ascii FOO=bar :are supported by this server\r\n").unwrap();
+        parser.apply_isupport(&isupport);
+        assert!(parser.is_channel("#chan"));
+        assert!(!parser.is_channel("&local"));
+        assert_eq!(parser.chantypes(), "#");
+        assert_eq!(parser.member_prefixes(), "@+");
+        assert_eq!(parser.nicklen(), 30);
+        assert!(parser.is_valid_nick("a_long_nickname"));
+        assert_eq!(parser.casemapping(), CaseMapping::Ascii);
+        assert!(!parser.irc_eq("nick[]", "NICK{}"));
+        let msg = parser.parse(":op!u@h MODE #chan +ovj-lk nick1 nick2 5:10 key\r\n").unwrap();
+        assert_eq!(parser.parse_mode_params(&msg.params[1..]), vec![
+            ModeChange { add: true, mode: 'o', arg: Some("nick1") },
+            ModeChange { add: true, mode: 'v', arg: Some("nick2") },
+            ModeChange { add: true, mode: 'j', arg: Some("5:10") },
+            ModeChange { add: false, mode: 'l', arg: None },
+            ModeChange { add: false, mode: 'k', arg: Some("key") }
+        ]);
+        let msg = parser.parse(":server 324 me #chan +ntj 5:10\r\n").unwrap();
+        assert_eq!(parser.parse_channel_mode_reply(&msg).unwrap().1[2], ModeChange { add: true, mode: 'j', arg: Some("5:10") });
+        let msg = parser.parse(":server 353 me = #chan :@op +voice ~founder\r\n").unwrap();
+        let nicks: Vec<_> = parser.parse_names_reply(&msg).unwrap().members.iter().map(|member| member.nick).collect();
+        assert_eq!(nicks, vec!["op", "voice", "~founder"]);
+        assert_eq!(parser.channels(&parser.parse("PRIVMSG #a,&b :hi\r\n").unwrap()), vec!["#a"]);
+        // Malformed values leave the context as it was
+        parser.apply_isupport(&parser.parse(":server 005 me PREFIX=(ov)@ NICKLEN=x CASEMAPPING=rfc7613 :are supported\r\n").unwrap());
+        assert_eq!((parser.member_prefixes(), parser.nicklen(), parser.casemapping()), ("@+", 30, CaseMapping::Ascii));
+        parser.apply_isupport(&parser.parse(":server 005 me PREFIX= :are supported\r\n").unwrap());
+        assert_eq!(parser.member_prefixes(), "");
+    }
+    #[test]
+    fn test_parse_prefix_token() {
+        assert_eq!(parse_prefix_token("(ohv)@%+"), Some(vec![('o', '@'), ('h', '%'), ('v', '+')]));
+        assert_eq!(parse_prefix_token("(qaohv)~&@%+").map(|pairs| pairs.len()), Some(5));
+        assert_eq!(parse_prefix_token("()"), Some(vec![]));
+        for value in &["", "ohv@%+", "(ohv@%+", "(ohv)@%", "(ov)@%+"] {
+            assert_eq!(parse_prefix_token(value), None, "{}", value);
+        }
+    }
+    #[test]
+    fn test_parse_who_reply() {
+        let msg = parse_message(":server 352 me #chan ~user host.example irc.example nick H@ :3 Real Name\r\n").unwrap();
+        assert_eq!(parse_who_reply(&msg), Some(WhoReply {
+            channel: "#chan",
+            user: "~user",
+            host: "host.example",
+            server: "irc.example",
+            nick: "nick",
+            flags: "H@",
+            hopcount: 3,
+            realname: "Real Name"
+        }));
+        let msg = parse_message(":server 352 me * user host irc.example nick G :0\r\n").unwrap();
+        assert_eq!(parse_who_reply(&msg).map(|reply| (reply.hopcount, reply.realname)), Some((0, "")));
+        let msg = parse_message(":server 352 me #chan user host irc.example nick H :x Real Name\r\n").unwrap();
+        assert_eq!(parse_who_reply(&msg), None);
+        let msg = parse_message(":server 352 me #chan user host\r\n").unwrap();
+        assert_eq!(parse_who_reply(&msg), None);
+        let msg = parse_message(":server 315 me #chan :End of WHO list\r\n").unwrap();
+        assert_eq!(parse_who_reply(&msg), None);
+    }
+    #[test]
+    fn test_parse_list_reply() {
+        let msg = parse_message(":server 322 me #rust 42 :[+nt] Rust discussion\r\n").unwrap();
+        assert_eq!(parse_list_reply(&msg), Some(ListEntry { channel: "#rust", users: 42, topic: "[+nt] Rust discussion" }));
+        let msg = parse_message(":server 322 me #empty 1\r\n").unwrap();
+        assert_eq!(parse_list_reply(&msg), Some(ListEntry { channel: "#empty", users: 1, topic: "" }));
+        for line in &[":server 322 me #chan many :topic\r\n", ":server 322 me #chan -1 :topic\r\n",
+                      ":server 322 me #chan\r\n", ":server 323 me :End of /LIST\r\n"] {
+            assert_eq!(parse_list_reply(&parse_message(line).unwrap()), None, "{}", line);
+        }
+    }
+    #[test]
+    fn test_parse_metadata() {
+        let msg = parse_message(":irc.example.com METADATA nick url * :http://www.example.com\r\n").unwrap();
+        assert_eq!(parse_metadata(&msg), Some(Metadata {
+            target: "nick",
+            key: "url",
+            visibility: "*",
+            value: Some("http://www.example.com")
+        }));
+        let msg = parse_message(":irc.example.com METADATA #chan topic.color * \r\n").unwrap();
+        assert_eq!(parse_metadata(&msg).map(|metadata| (metadata.key, metadata.value)), Some(("topic.color", None)));
+        assert_eq!(parse_metadata(&parse_message("METADATA nick url\r\n").unwrap()), None);
+        assert_eq!(parse_metadata(&parse_message(":server 761 me nick url * :value\r\n").unwrap()), None);
+    }
+    #[test]
+    fn test_parse_mono() {
+        let msg = parse_message(":server 730 me :alice!a@host.example,bob\r\n").unwrap();
+        assert_eq!(parse_mono(&msg), Some((true, vec![
+            Prefix::User { nick: "alice", user: Some("a"), host: Some("host.example") },
+            Prefix::User { nick: "bob", user: None, host: None }
+        ])));
+        let msg = parse_message(":server 731 me :carol\r\n").unwrap();
+        assert_eq!(parse_mono(&msg), Some((false, vec![Prefix::User { nick: "carol", user: None, host: None }])));
+        assert_eq!(parse_mono(&parse_message(":server 731 me :\r\n").unwrap()), Some((false, vec![])));
+        assert_eq!(parse_mono(&parse_message(":server 730 me :alice!@host\r\n").unwrap()), None);
+        assert_eq!(parse_mono(&parse_message(":server 730 me\r\n").unwrap()), None);
+        assert_eq!(parse_mono(&parse_message(":server 732 me :alice\r\n").unwrap()), None);
+    }
+    #[test]
+    fn test_owned_message_from_str() {
+        let msg: OwnedMessage = ":nick!user@host PRIVMSG #chan :Hello\r\n".parse().unwrap();
+        assert_eq!(msg.command, OwnedCommand::Named("PRIVMSG".to_string()));
+        assert_eq!(msg.params, vec!["#chan", "Hello"]);
+        let msg: OwnedMessage = "PING :server".parse().unwrap();
+        assert_eq!(msg.params, vec!["server"]);
+        assert_eq!("".parse::<OwnedMessage>(), Err(ParserError::MissingCommand { position: 0 }));
+        assert_eq!(": BROKEN".parse::<OwnedMessage>(), Err(ParserError::MalformedPrefix { position: 0 }));
+        assert_eq!("PING :a\r\nPING :b\r\n".parse::<OwnedMessage>(), Err(ParserError::TrailingInput { position: 9 }));
+        assert_eq!("PING :a\nPING :b".parse::<OwnedMessage>(), Err(ParserError::TrailingInput { position: 8 }));
+    }
+    #[test]
+    fn test_client_only_tags() {
+        let line = "@+example.com/reply=123;time=now;+typing=active :nick!user@host TAGMSG #chan\r\n";
+        let msg = parse_message(line).unwrap();
+        assert!(is_client_only_tag("+typing"));
+        assert!(!is_client_only_tag("time"));
+        assert_eq!(msg.client_only_tags().collect::<Vec<_>>(), vec![&("+example.com/reply", Some("123")), &("+typing", Some("active"))]);
+        assert_eq!(msg.server_tags().collect::<Vec<_>>(), vec![&("time", Some("now"))]);
+        assert_eq!(msg.raw_tag("+typing"), Some("active"));
+        assert_eq!(msg.to_string(), line);
+    }
+    #[test]
+    fn test_pong_reply() {
+        let msg = parse_message("PING :irc.example.com\r\n").unwrap();
+        assert!(msg.is_ping());
+        let pong = msg.pong_reply().unwrap();
+        assert_eq!(pong.to_string(), "PONG :irc.example.com\r\n");
+        let line = pong.to_string();
+        let parsed = parse_message(&line).unwrap();
+        assert!(parsed.command.matches("PONG"));
+        assert_eq!(parsed.params, vec!["irc.example.com"]);
+        assert_eq!(parse_message("ping token\r\n").unwrap().pong_reply().unwrap().to_string(), "PONG :token\r\n");
+        assert!(!parse_message("PONG :token\r\n").unwrap().is_ping());
+    }
+    #[test]
+    fn test_ping_token() {
+        assert_eq!(parse_message("PING :irc.example.com\r\n").unwrap().ping_token(), Some("irc.example.com"));
+        assert_eq!(parse_message("PING irc.example.com\r\n").unwrap().ping_token(), Some("irc.example.com"));
+        let msg = parse_message("PING server1 server2\r\n").unwrap();
+        assert_eq!(msg.ping_token(), Some("server1"));
+        assert_eq!(msg.pong_reply().unwrap().to_string(), "PONG :server1\r\n");
+        assert_eq!(parse_message("PING server1 :server2\r\n").unwrap().ping_token(), Some("server1"));
+        assert_eq!(parse_message("PING\r\n").unwrap().ping_token(), None);
+        assert_eq!(parse_message("PING\r\n").unwrap().pong_reply().unwrap().to_string(), "PONG\r\n");
+        assert_eq!(parse_message("PONG :token\r\n").unwrap().ping_token(), None);
+    }
+    #[test]
+    fn test_command_kind_helpers() {
+        let numeric = parse_message(":server 433 * nick :Nickname is already in use\r\n").unwrap().command;
+        assert!(numeric.is_numeric());
+        assert_eq!(numeric.as_numeric(), Some(433));
+        assert_eq!(numeric.as_named(), None);
+        let named = parse_message("PRIVMSG #chan :hi\r\n").unwrap().command;
+        assert!(!named.is_numeric());
+        assert_eq!(named.as_numeric(), None);
+        assert_eq!(named.as_named(), Some("PRIVMSG"));
+    }
+    #[test]
+    fn test_parse_names_reply() {
+        let msg = parse_message(":server 353 me = #chan :@+op +voiced regular ~owner \r\n").unwrap();
+        let reply = parse_names_reply(&msg).unwrap();
+        assert_eq!(reply.symbol, "=");
+        assert_eq!(reply.channel, "#chan");
+        assert_eq!(reply.members, vec![
+            NamesMember { prefixes: "@+", nick: "op" },
+            NamesMember { prefixes: "+", nick: "voiced" },
+            NamesMember { prefixes: "", nick: "regular" },
+            NamesMember { prefixes: "~", nick: "owner" }
+        ]);
+        let highest: Vec<_> = reply.members.iter().map(|member| member.highest_prefix()).collect();
+        assert_eq!(highest, vec![Some('@'), Some('+'), None, Some('~')]);
+
+        let msg = parse_message(":server 353 me @ #secret :!admin @op\r\n").unwrap();
+        let reply = parse_names_reply_with_prefixes(&msg, "!@").unwrap();
+        assert_eq!(reply.symbol, "@");
+        assert_eq!(reply.members[0], NamesMember { prefixes: "!", nick: "admin" });
+        assert_eq!(parse_names_reply(&parse_message(":server 366 me #chan :End of NAMES list\r\n").unwrap()), None);
+    }
+    #[test]
+    fn test_parsing_prefix_shapes_in_messages() {
+        let cases = vec![
+            (":nick PRIVMSG #chan :hi\r\n", Prefix::User { nick: "nick", user: None, host: None }),
+            (":nick@host.example.com PRIVMSG #chan :hi\r\n", Prefix::User { nick: "nick", user: None, host: Some("host.example.com") }),
+            (":nick!~user@host.example.com PRIVMSG #chan :hi\r\n", Prefix::User { nick: "nick", user: Some("~user"), host: Some("host.example.com") })
+        ];
+        for (input, expected) in cases {
+            let msg = parse_message(input).unwrap();
+            assert_eq!(msg.prefix, Some(expected));
+            assert_eq!(msg.params, vec!["#chan", "hi"]);
+        }
+    }
+    #[test]
+    fn test_parse_cap() {
+        let msg = parse_message(":server CAP * LS :multi-prefix sasl=PLAIN,EXTERNAL\r\n").unwrap();
+        assert_eq!(parse_cap(&msg), Some(CapMessage { subcommand: "LS", target: "*", caps: vec!["multi-prefix", "sasl=PLAIN,EXTERNAL"], more: false }));
+        let msg = parse_message(":server CAP nick LS * :away-notify account-notify\r\n").unwrap();
+        assert_eq!(parse_cap(&msg), Some(CapMessage { subcommand: "LS", target: "nick", caps: vec!["away-notify", "account-notify"], more: true }));
+        let msg = parse_message(":server CAP nick ACK :sasl \r\n").unwrap();
+        assert_eq!(parse_cap(&msg).map(|cap| cap.caps), Some(vec!["sasl"]));
+        assert_eq!(parse_cap(&parse_message(":server CAP nick LS x :sasl\r\n").unwrap()), None);
+        assert_eq!(parse_cap(&parse_message("PRIVMSG #chan :CAP\r\n").unwrap()), None);
+    }
+    #[test]
+    fn test_to_whitespace_separated() {
+        let msg = parse_message("@time=now :nick!user@host PRIVMSG #chan :Hello there\r\n").unwrap();
+        assert_eq!(msg.to_whitespace_separated(), "nick!user@host PRIVMSG #chan Hello there");
+        let msg = parse_message("PING :token\r\n").unwrap();
+        assert_eq!(msg.to_whitespace_separated(), "PING token");
+    }
+    #[test]
+    fn test_kick_info() {
+        let msg = parse_message(":op!user@host KICK #chan nick :Behave\r\n").unwrap();
+        assert_eq!(msg.kick_info(), Some(vec![KickInfo { channel: "#chan", nick: "nick", reason: Some("Behave") }]));
+        let msg = parse_message(":op!user@host KICK #chan nick\r\n").unwrap();
+        assert_eq!(msg.kick_info(), Some(vec![KickInfo { channel: "#chan", nick: "nick", reason: None }]));
+        let msg = parse_message("KICK #a,#b n1,n2\r\n").unwrap();
+        assert_eq!(msg.kick_info(), Some(vec![
+            KickInfo { channel: "#a", nick: "n1", reason: None },
+            KickInfo { channel: "#b", nick: "n2", reason: None }
+        ]));
+        let msg = parse_message("KICK #a n1,n2 :Bye\r\n").unwrap();
+        assert_eq!(msg.kick_info(), Some(vec![
+            KickInfo { channel: "#a", nick: "n1", reason: Some("Bye") },
+            KickInfo { channel: "#a", nick: "n2", reason: Some("Bye") }
+        ]));
+        assert_eq!(parse_message("KICK #chan\r\n").unwrap().kick_info(), None);
+        assert_eq!(parse_message("PART #chan\r\n").unwrap().kick_info(), None);
+    }
+    #[test]
+    fn test_split_privmsg() {
+        let source = "nick!user@host.example.com";
+        assert_eq!(split_privmsg("#chan", "hello", source.len()).unwrap(), vec!["PRIVMSG #chan :hello\r\n"]);
+        assert!(split_privmsg("#chan", "", source.len()).unwrap().is_empty());
+
+        let text = "ä".repeat(300);
+        let lines = split_privmsg("#chan", &text, source.len()).unwrap();
+        assert_eq!(lines.len(), 2);
+        for line in &lines {
+            assert!(":".len() + source.len() + " ".len() + line.len() <= MAX_LINE_LENGTH);
+        }
+        let joined: String = lines.iter()
+            .map(|line| parse_message(line).unwrap().params[1].to_string())
+            .collect();
+        assert_eq!(joined, text);
+
+        let text = "x".repeat(1000);
+        let lines = split_privmsg("#chan", &text, source.len()).unwrap();
+        assert_eq!(":".len() + source.len() + " ".len() + lines[0].len(), MAX_LINE_LENGTH);
+        let overhead = ":".len() + 500 + " PRIVMSG #chan :\r\n".len();
+        assert_eq!(split_privmsg("#chan", "hello", 500), Err(ParserError::LineTooLong { len: overhead + 1 }));
+        assert_eq!(split_privmsg("#a b", "x", 10), Err(ParserError::InvalidParam { index: 0 }));
+        assert_eq!(split_privmsg("", "x", 10), Err(ParserError::InvalidParam { index: 0 }));
+        assert_eq!(split_privmsg("#chan", "a\0b", 10), Err(ParserError::InvalidParam { index: 1 }));
+
+        let lines = split_privmsg("#chan", "hi\r\nQUIT :bye\n\nagain\r", source.len()).unwrap();
+        assert_eq!(lines, vec!["PRIVMSG #chan :hi\r\n", "PRIVMSG #chan :QUIT :bye\r\n", "PRIVMSG #chan :again\r\n"]);
+        assert!(split_privmsg("#chan", "\r\n", source.len()).unwrap().is_empty());
+    }
+    #[test]
+    fn test_parse_topic() {
+        let msg = parse_message(":server.example.com 332 RustBot #channel :Welcome to the channel\r\n").unwrap();
+        let topic = parse_topic(&msg).unwrap();
+        assert_eq!(topic, Topic::Text { channel: "#channel", text: "Welcome to the channel" });
+        assert_eq!(topic.set_time(), None);
+        let msg = parse_message(":server.example.com 333 RustBot #channel user!host@example.com 123456789\r\n").unwrap();
+        let topic = parse_topic(&msg).unwrap();
+        assert_eq!(topic, Topic::SetBy { channel: "#channel", setter: "user!host@example.com", time: "123456789" });
+        assert_eq!(topic.channel(), "#channel");
+        assert_eq!(topic.set_time(), Some(123456789));
+        let msg = parse_message(":server.example.com 333 RustBot #channel nick soon\r\n").unwrap();
+        assert_eq!(parse_topic(&msg).unwrap().set_time(), None);
+        assert_eq!(parse_topic(&parse_message(":server 331 RustBot #channel :No topic is set\r\n").unwrap()), None);
+    }
+    #[test]
+    fn test_reply_target() {
+        let msg = parse_message(":nick!user@host PRIVMSG #chan :hi\r\n").unwrap();
+        assert_eq!(msg.reply_target(), Some("#chan"));
+        let msg = parse_message(":nick!user@host PRIVMSG RustBot :hi\r\n").unwrap();
+        assert_eq!(msg.reply_target(), Some("nick"));
+        let msg = parse_message(":nick!user@host NOTICE &local :hi\r\n").unwrap();
+        assert_eq!(msg.reply_target(), Some("&local"));
+        assert_eq!(parse_message("PRIVMSG RustBot :hi\r\n").unwrap().reply_target(), None);
+        assert_eq!(parse_message(":nick!user@host JOIN #chan\r\n").unwrap().reply_target(), None);
+    }
+    #[test]
+    fn test_presence_changes() {
+        assert_eq!(parse_message(":nick!user@host ACCOUNT bob\r\n").unwrap().account_change(), Some(Some("bob")));
+        assert_eq!(parse_message(":nick!user@host ACCOUNT *\r\n").unwrap().account_change(), Some(None));
+        assert_eq!(parse_message(":nick!user@host ACCOUNT\r\n").unwrap().account_change(), None);
+        assert_eq!(parse_message(":nick!user@host AWAY :Gone fishing\r\n").unwrap().away_status(), Some(Some("Gone fishing")));
+        assert_eq!(parse_message(":nick!user@host AWAY\r\n").unwrap().away_status(), Some(None));
+        assert_eq!(parse_message(":nick!user@host AWAY :\r\n").unwrap().away_status(), Some(None));
+        assert_eq!(parse_message(":nick!user@host PRIVMSG #chan :AWAY\r\n").unwrap().away_status(), None);
+    }
+    #[test]
+    fn test_parse_into() {
+        let input = "@time=now :nick!user@host PRIVMSG #chan :first\r\nPING :second\r\n: BROKEN\r\n";
+        let mut lines = input.split_inclusive('\n');
+        let mut msg = Message::default();
+        parse_into(&mut msg, lines.next().unwrap()).unwrap();
+        assert_eq!(msg.tags, vec![("time", Some("now"))]);
+        assert_eq!(msg.params, vec!["#chan", "first"]);
+        let capacity = msg.params.capacity();
+        parse_into(&mut msg, lines.next().unwrap()).unwrap();
+        assert!(msg.tags.is_empty());
+        assert_eq!(msg.prefix, None);
+        assert!(msg.command.matches("PING"));
+        assert_eq!(msg.params, vec!["second"]);
+        assert_eq!(msg.raw, "PING :second");
+        assert_eq!(msg.params.capacity(), capacity);
+        assert_eq!(parse_into(&mut msg, lines.next().unwrap()), Err(ParserError::MalformedPrefix { position: 0 }));
+    }
+    #[test]
+    fn test_msgid_and_label() {
+        let msg = parse_message("@msgid=abc\\s123;label=req1 :nick!user@host PRIVMSG #chan :hi\r\n").unwrap();
+        assert_eq!(msg.msgid().as_deref(), Some("abc 123"));
+        assert_eq!(msg.label().as_deref(), Some("req1"));
+        let msg = parse_message("@time=now :nick!user@host PRIVMSG #chan :hi\r\n").unwrap();
+        assert_eq!(msg.msgid(), None);
+        assert_eq!(parse_message("PING :x\r\n").unwrap().label(), None);
+    }
+    #[test]
+    fn test_collapsing_space_runs_between_params() {
+        let msg = parse_message("CMD  a   b\r\n").unwrap();
+        assert!(msg.command.matches("CMD"));
+        assert_eq!(msg.params, vec!["a", "b"]);
+        assert!(!msg.has_trailing);
+        let msg = parse_message(":nick!user@host   CMD  a   :  spaced   out  \r\n").unwrap();
+        assert_eq!(msg.prefix, Some(Prefix::User { nick: "nick", user: Some("user"), host: Some("host") }));
+        assert_eq!(msg.params, vec!["a", "  spaced   out  "]);
+        let msg = parse_message("CMD a   \r\n").unwrap();
+        assert_eq!(msg.params, vec!["a"]);
+    }
+    #[test]
+    fn test_prefix_kind_predicates() {
+        let msg = parse_message(":irc.example.com NOTICE * :hello\r\n").unwrap();
+        assert!(msg.prefix.as_ref().unwrap().is_server());
+        assert!(!msg.prefix.as_ref().unwrap().is_user());
+        assert!(msg.is_from_server());
+        let msg = parse_message(":nick!user@host PRIVMSG #chan :hi\r\n").unwrap();
+        assert!(msg.prefix.as_ref().unwrap().is_user());
+        assert!(!msg.prefix.as_ref().unwrap().is_server());
+        assert!(!msg.is_from_server());
+        assert!(!parse_message("PING :x\r\n").unwrap().is_from_server());
+    }
+    #[test]
+    fn test_nick_change() {
+        assert_eq!(parse_message(":old!user@host NICK new\r\n").unwrap().nick_change(), Some(("old", "new")));
+        assert_eq!(parse_message(":old!user@host NICK :new\r\n").unwrap().nick_change(), Some(("old", "new")));
+        assert_eq!(parse_message("NICK new\r\n").unwrap().nick_change(), None);
+        assert_eq!(parse_message(":irc.example.com NICK new\r\n").unwrap().nick_change(), None);
+        assert_eq!(parse_message(":old!user@host NICK\r\n").unwrap().nick_change(), None);
+        assert_eq!(parse_message(":old!user@host QUIT :new\r\n").unwrap().nick_change(), None);
+    }
+    // Runs every accessor on `msg`. Kept in sync with the fuzz target.
+    fn exercise_message(msg: &Message<'_>) {
+        let _ = (msg.to_string(), msg.to_owned(), msg.to_bytes(), msg.command_str(), msg.to_whitespace_separated());
+        let _ = (msg.trailing(), msg.params_with_trailing(), msg.params_after(1), msg.numeric_args(), msg.is_error_numeric());
+        let _ = (msg.sender_nick(), msg.privmsg_target(), msg.privmsg_text(), msg.ctcp().as_ref().and_then(parse_dcc), msg.msgid());
+        let _ = (msg.join_channels(), msg.kick_info(), msg.reply_target(), msg.nick_change(), msg.is_echo("me"));
+        let _ = (msg.account_change(), msg.away_status(), msg.ping_token(), msg.pong_reply(), msg.quit_reason(), msg.part_info());
+        let _ = (msg.error_text(), msg.wallops_text(), msg.invite_info(), msg.topic_command(), msg.sasl_payload());
+        let _ = (msg.channels(), msg.channels_with_types(""), msg.raw_tag("a"), msg.tag("a"), msg.tag_value("a"), msg.has_tag("a"));
+        let _ = (msg.client_only_tags().count(), msg.server_tags().count(), msg.server_time_millis(), msg.batch(), msg.label());
+        let _ = (parse_isupport(msg), parse_who_reply(msg), parse_names_reply(msg), parse_topic(msg), parse_cap(msg), parse_batch(msg));
+        let _ = (parse_list_reply(msg), parse_metadata(msg), parse_mono(msg), parse_channel_mode_reply(msg));
+        let _ = parse_mode_params(&msg.params, "ovbk");
+        for param in &msg.params {
+            let _ = (strip_formatting(param), parse_prefix_token(param), is_valid_nick(param));
+        }
+    }
+    // Runs every parser and accessor on `input`, which must not panic whatever it holds
+    fn exercise(input: &[u8]) {
+        if let Ok(msg) = parse_message_bytes(input) {
+            exercise_message(&msg);
+            // The fields are public, so the accessors mustn't rely on them being consistent
+            let mut msg = msg.clone();
+            msg.has_trailing = !msg.has_trailing;
+            exercise_message(&msg);
+        }
+        if let Ok(input) = core::str::from_utf8(input) {
+            let _ = parse_messages(input);
+            let _ = input.parse::<OwnedMessage>();
+        }
+        let mut stream = MessageStream::new();
+        stream.push(input);
+        while stream.next_message().is_some() {}
+    }
+
+    #[test]
+    fn test_adversarial_inputs_dont_panic() {
+        let inputs: Vec<&[u8]> = vec![
+            b"", b" ", b"   \r\n", b"\t\t", b":", b":\r\n", b"@", b"@\r\n", b"@ \r\n", b"@;;; CMD\r\n",
+            b"@a=\\ CMD\r\n", b": \r\n", b"::\r\n", b":!@ CMD\r\n", b":a!b@c\r\n", b"\0\0\0\r\n",
+            b"PRIVMSG \0 :\0\r\n", b"\xff\xfe\r\n", b":\xff CMD\r\n", b"@\xff CMD\r\n", b"CMD :\xc3\r\n",
+            b"\r", b"\n", b"\r\r\n\n", b"PRIVMSG #chan :\x01\r\n", b"NOTICE x :\x01\x01\r\n", b"MODE #chan +k\r\n",
+            b":srv 005 \r\n", b":srv 005 me :\r\n", b":srv 353\r\n", b"KICK , ,\r\n", b"JOIN ,,,\r\n", b"CAP * LS *\r\n"
+        ];
+        for input in inputs {
+            exercise(input);
+        }
+        let long = [b'a'; 2 * MAX_LINE_LENGTH];
+        exercise(&long);
+    }
+
+    #[test]
+    fn test_short_inputs_dont_panic() {
+        // Every combination of up to four of the bytes the grammar cares about
+        let alphabet: &[u8] = b":@! \r\n;=a\0\xff\x01";
+        let mut input = Vec::new();
+        fn combinations(alphabet: &[u8], input: &mut Vec<u8>, left: usize) {
+            exercise(input);
+            if left == 0 {
+                return;
+            }
+            for &b in alphabet {
+                input.push(b);
+                combinations(alphabet, input, left - 1);
+                input.pop();
+            }
+        }
+        combinations(alphabet, &mut input, 4);
+    }
+    #[test]
+    fn test_quit_and_part_reasons() {
+        assert_eq!(parse_message(":nick!user@host QUIT :Ping timeout\r\n").unwrap().quit_reason(), Some("Ping timeout"));
+        assert_eq!(parse_message(":nick!user@host QUIT\r\n").unwrap().quit_reason(), None);
+        assert_eq!(parse_message(":nick!user@host QUIT :\r\n").unwrap().quit_reason(), None);
+        assert_eq!(parse_message(":nick!user@host PART #chan :Bye\r\n").unwrap().quit_reason(), None);
+        assert_eq!(parse_message(":nick!user@host PART #a,#b :Bye\r\n").unwrap().part_info(), Some((vec!["#a", "#b"], Some("Bye"))));
+        assert_eq!(parse_message(":nick!user@host PART #chan\r\n").unwrap().part_info(), Some((vec!["#chan"], None)));
+        assert_eq!(parse_message(":nick!user@host PART\r\n").unwrap().part_info(), None);
+        assert_eq!(parse_message(":nick!user@host QUIT :Bye\r\n").unwrap().part_info(), None);
+    }
+    #[test]
+    fn test_command_str() {
+        assert_eq!(parse_message(":server 004 nick a b c\r\n").unwrap().command_str(), "004");
+        assert_eq!(parse_message(":server 433 * nick :In use\r\n").unwrap().command_str(), "433");
+        let msg = parse_message("PRIVMSG #chan :hi\r\n").unwrap();
+        assert!(matches!(msg.command_str(), Cow::Borrowed("PRIVMSG")));
+    }
+    #[test]
+    fn test_server_labels() {
+        assert_eq!(Prefix::Server("port80a.se.quakenet.org").server_labels(), Some(vec!["port80a", "se", "quakenet", "org"]));
+        assert_eq!(Prefix::Server("localhost").server_labels(), Some(vec!["localhost"]));
+        assert_eq!(Prefix::Server("irc..example.org").server_labels(), None);
+        assert_eq!(Prefix::Server("irc.example.org.").server_labels(), None);
+        assert_eq!(Prefix::User { nick: "nick", user: None, host: None }.server_labels(), None);
+    }
+    #[test]
+    fn test_comparing_commands() {
+        let msg = parse_message("privmsg #chan :hi\r\n").unwrap();
+        assert!(msg.command == "PRIVMSG");
+        assert!(msg.command == "privmsg");
+        assert!(msg.command != "NOTICE");
+        assert!(msg.command != 433);
+        let msg = parse_message(":server 433 * nick :In use\r\n").unwrap();
+        assert!(msg.command == 433);
+        assert!(msg.command != 432);
+        assert!(msg.command != "433");
+    }
+    #[test]
+    fn test_parse_batch() {
+        let msg = parse_message(":irc.example.com BATCH +yXNAbvnRHTRBv netsplit irc.hub other.host\r\n").unwrap();
+        assert_eq!(parse_batch(&msg), Some(BatchMarker::Open { reference: "yXNAbvnRHTRBv", kind: "netsplit", params: vec!["irc.hub", "other.host"] }));
+        let msg = parse_message(":irc.example.com BATCH +abc chathistory\r\n").unwrap();
+        assert_eq!(parse_batch(&msg), Some(BatchMarker::Open { reference: "abc", kind: "chathistory", params: vec![] }));
+        let msg = parse_message(":irc.example.com BATCH -abc\r\n").unwrap();
+        assert_eq!(parse_batch(&msg), Some(BatchMarker::Close { reference: "abc" }));
+        assert_eq!(parse_batch(&parse_message(":irc.example.com BATCH abc\r\n").unwrap()), None);
+        assert_eq!(parse_batch(&parse_message(":irc.example.com BATCH +abc\r\n").unwrap()), None);
+        let msg = parse_message("@batch=abc :nick!user@host PRIVMSG #chan :hi\r\n").unwrap();
+        assert_eq!(msg.batch().as_deref(), Some("abc"));
+        assert_eq!(parse_batch(&msg), None);
+    }
+    #[test]
+    fn test_server_time_millis() {
+        let msg = parse_message("@time=2021-07-21T19:20:00.123Z :nick!user@host PRIVMSG #chan :hi\r\n").unwrap();
+        assert_eq!(msg.server_time_millis(), Some(1_626_895_200_123));
+        let msg = parse_message("@time=1970-01-01T00:00:00Z PING :x\r\n").unwrap();
+        assert_eq!(msg.server_time_millis(), Some(0));
+        assert_eq!(parse_server_time("2000-02-29T23:59:59Z"), Some(951_868_799_000));
+        assert_eq!(parse_server_time("1969-12-31T23:59:59.5Z"), Some(-500));
+        assert_eq!(parse_server_time("2021-07-21T19:20:00.123456Z"), Some(1_626_895_200_123));
+        for malformed in &["2021-07-21T19:20:00", "2021-07-21 19:20:00Z", "2021-13-01T00:00:00Z", "2021-02-29T00:00:00Z",
+                           "2021-07-21T24:00:00Z", "2021-7-21T19:20:00Z", "2021-07-21T19:20:00.Z", "2021-07-21T19:20:00.12aZ", ""] {
+            assert_eq!(parse_server_time(malformed), None, "{}", malformed);
+        }
+        assert_eq!(parse_message("PING :x\r\n").unwrap().server_time_millis(), None);
+    }
+    #[test]
+    fn test_to_wire_string() {
+        let line = "@time=now :nick!user@host PRIVMSG #chan :Hello there\r\n";
+        let msg = parse_message(line).unwrap();
+        assert_eq!(msg.to_wire_string(), line);
+        assert_eq!(msg.to_wire_string_with("\n"), "@time=now :nick!user@host PRIVMSG #chan :Hello there\n");
+        let built = MessageBuilder::new().command("PRIVMSG").param("#chan").param("word").build().unwrap();
+        let wire = built.as_message().to_wire_string();
+        assert_eq!(wire, "PRIVMSG #chan word\r\n");
+        assert_eq!(parse_message(&wire).unwrap().to_owned(), built);
+        let built = MessageBuilder::new().command("TOPIC").param("#chan").param(":colon").build().unwrap();
+        let wire = built.as_message().to_wire_string();
+        assert_eq!(wire, "TOPIC #chan ::colon\r\n");
+        assert_eq!(parse_message(&wire).unwrap().params, built.params);
+    }
+    #[test]
+    fn test_to_bytes() {
+        let lines: &[&[u8]] = &[
+            b"@time=2021-07-21T19:20:00Z;+draft/reply=a\\sb :nick!user@host PRIVMSG #chan :Hello there\r\n",
+            b":irc.example.com 005 me CHANTYPES=# NICKLEN=30 :are supported by this server\r\n",
+            b"PING :\r\n", b"JOIN #chan key\r\n", b"PRIVMSG #chan :h\xc3\xa4\r\n"
+        ];
+        for &line in lines {
+            assert_eq!(parse_message_bytes(line).unwrap().to_bytes(), line, "{}", String::from_utf8_lossy(line));
+        }
+        assert_eq!(Message::privmsg("#chan", "hi").unwrap().to_bytes(), b"PRIVMSG #chan :hi\r\n");
+    }
+    #[test]
+    fn test_to_bytes_keeps_invalid_utf8() {
+        let line = b":nick!user@host PRIVMSG #k\xe4\xe4k :caf\xe9 au lait\r\n";
+        let msg = parse_message_bytes(line).unwrap();
+        assert_eq!(msg.to_bytes(), line);
+        let mut written = Vec::new();
+        msg.write_to(&mut written).unwrap();
+        assert_eq!(written, line);
+        let mut changed = msg.clone();
+        changed.params[1] = Cow::Borrowed("tea");
+        assert_eq!(changed.to_bytes(), ":nick!user@host PRIVMSG #k\u{fffd}\u{fffd}k :tea\r\n".as_bytes());
+    }
+    #[test]
+    fn test_builder_round_trips_params() {
+        let built = MessageBuilder::new().command("MODE").param("#chan").param("+o").param("nick").build().unwrap();
+        assert_eq!(parse_message(&built.to_string()).unwrap().to_owned(), built);
+        let built = MessageBuilder::new().command("PRIVMSG").param("#chan").param("two words").build().unwrap();
+        assert_eq!(parse_message(&built.to_string()).unwrap().params, built.params);
+    }
+    #[test]
+    fn test_builder_rejects_invalid_params() {
+        let invalid = |index| Err(ParserError::InvalidParam { index });
+        assert_eq!(MessageBuilder::new().command("PRIVMSG").param("#a #b").trailing("hi").build(), invalid(0));
+        assert_eq!(MessageBuilder::new().command("USER").param("").param("0").param("*").trailing("Real Name").build(), invalid(0));
+        assert_eq!(MessageBuilder::new().command("TOPIC").param(":chan").param("topic").build(), invalid(0));
+        assert_eq!(MessageBuilder::new().command("PRIVMSG").param("#c").trailing("hi\r\nQUIT :x").build(), invalid(1));
+        assert_eq!(MessageBuilder::new().command("PRIVMSG").param("#c").param("hi\nQUIT").build(), invalid(1));
+        assert_eq!(MessageBuilder::new().command("PRIVMSG").param("#c\0").trailing("hi").build(), invalid(0));
+        assert_eq!(MessageBuilder::new().command("PONG").trailing("a\rb").build(), invalid(0));
+        assert!(MessageBuilder::new().command("PRIVMSG").param("#c").param(":hi there").build().is_ok());
+    }
+    #[test]
+    fn test_builder_rejects_invalid_command_and_prefix() {
+        let malformed_command = Err(ParserError::MalformedCommand { position: 0 });
+        assert_eq!(MessageBuilder::new().command("PRIVMSG #a :x\r\nQUIT").build(), malformed_command);
+        assert_eq!(MessageBuilder::new().command("").build(), malformed_command);
+        assert_eq!(MessageBuilder::new().numeric(1000).build(), malformed_command);
+        assert_eq!(MessageBuilder::new().numeric(999).build().unwrap().to_string(), "999\r\n");
+        assert_eq!(MessageBuilder::new().trailing("hi").build(), Err(ParserError::MissingCommand { position: 0 }));
+        let malformed_prefix = Err(ParserError::MalformedPrefix { position: 0 });
+        assert_eq!(MessageBuilder::new().prefix(Prefix::Server("a\r\nQUIT")).command("PING").build(), malformed_prefix);
+        assert_eq!(MessageBuilder::new().prefix(Prefix::Server("a b")).command("PING").build(), malformed_prefix);
+        let prefix = Prefix::User { nick: "nick", user: Some("user"), host: Some("ho\0st") };
+        assert_eq!(MessageBuilder::new().prefix(prefix).command("PING").build(), malformed_prefix);
+        let prefix = Prefix::User { nick: "nick", user: Some("us\ner"), host: None };
+        assert_eq!(MessageBuilder::new().prefix(prefix).command("PING").build(), malformed_prefix);
+    }
+    #[test]
+    fn test_error_text() {
+        let msg = parse_message("ERROR :Closing link: nick[host] (Excess Flood)\r\n").unwrap();
+        assert_eq!(msg.error_text(), Some("Closing link: nick[host] (Excess Flood)"));
+        assert_eq!(parse_message("ERROR\r\n").unwrap().error_text(), None);
+        assert_eq!(parse_message("NOTICE * :ERROR\r\n").unwrap().error_text(), None);
+    }
+    #[test]
+    fn test_irc_eq() {
+        assert!(irc_eq("Nick[]\\~", "nick{}|^", CaseMapping::Rfc1459));
+        assert!(irc_eq("#Chan{}", "#CHAN[]", CaseMapping::Rfc1459));
+        assert!(!irc_eq("nick~", "nick^", CaseMapping::Strict));
+        assert!(irc_eq("nick[]\\", "NICK{}|", CaseMapping::Strict));
+        assert!(!irc_eq("nick[]", "nick{}", CaseMapping::Ascii));
+        assert!(irc_eq("NiCk", "nick", CaseMapping::Ascii));
+        assert!(!irc_eq("nick", "nick_", CaseMapping::Rfc1459));
+        assert!(!irc_eq("Ä", "ä", CaseMapping::Rfc1459));
+        assert_eq!(CaseMapping::from_token("strict-rfc1459"), Some(CaseMapping::Strict));
+        assert_eq!(CaseMapping::from_token("rfc7613"), None);
+        assert_eq!(CaseMapping::default(), CaseMapping::Rfc1459);
+    }
+    #[test]
+    fn test_is_echo() {
+        let msg = parse_message(":Bot[away]!bot@host PRIVMSG #chan :hi\r\n").unwrap();
+        assert!(msg.is_echo("Bot[away]"));
+        assert!(msg.is_echo("bot{AWAY}"));
+        assert!(!msg.is_echo("Bot"));
+        assert!(parse_message(":me~|!u@h NOTICE #chan :hi\r\n").unwrap().is_echo("ME^\\"));
+        assert!(parse_message("@+typing=active :me TAGMSG #chan\r\n").unwrap().is_echo("me"));
+        assert!(!parse_message(":me!u@h JOIN #chan\r\n").unwrap().is_echo("me"));
+        assert!(!parse_message(":irc.example.com NOTICE * :hi\r\n").unwrap().is_echo("irc.example.com"));
+        assert!(!parse_message("PRIVMSG #chan :hi\r\n").unwrap().is_echo("me"));
+    }
+    #[test]
+    fn test_eq_ignore_prefix() {
+        let sent = Message::privmsg("#chan", "hello there").unwrap();
+        let echo = parse_message("@msgid=abc;time=2021-07-21T19:20:00Z :me!user@host privmsg #chan :hello there\r\n").unwrap();
+        assert!(echo.eq_ignore_prefix(&sent));
+        assert!(sent.eq_ignore_prefix(&echo));
+        assert!(parse_message("PRIVMSG #chan hello\r\n").unwrap().eq_ignore_prefix(&Message::privmsg("#chan", "hello").unwrap()));
+        assert!(!echo.eq_ignore_prefix(&Message::privmsg("#chan", "Hello there").unwrap()));
+        assert!(!echo.eq_ignore_prefix(&Message::notice("#chan", "hello there").unwrap()));
+        let numeric = parse_message(":server 001 me :Welcome\r\n").unwrap();
+        assert!(numeric.eq_ignore_prefix(&parse_message(":other 001 me :Welcome\r\n").unwrap()));
+        assert!(!numeric.eq_ignore_prefix(&parse_message(":server 002 me :Welcome\r\n").unwrap()));
+    }
+    #[test]
+    fn test_topic_command() {
+        let msg = parse_message(":nick!user@host TOPIC #chan :new topic\r\n").unwrap();
+        assert_eq!(msg.topic_command(), Some(("#chan", Some("new topic"))));
+        assert_eq!(parse_message("TOPIC #chan\r\n").unwrap().topic_command(), Some(("#chan", None)));
+        assert_eq!(parse_message("TOPIC #chan :\r\n").unwrap().topic_command(), Some(("#chan", Some(""))));
+        assert_eq!(parse_message("TOPIC\r\n").unwrap().topic_command(), None);
+        assert_eq!(parse_message(":server 332 nick #chan :topic\r\n").unwrap().topic_command(), None);
+    }
+    #[test]
+    fn test_wallops_text() {
+        let msg = parse_message(":oper!o@host WALLOPS :Server restarting soon\r\n").unwrap();
+        assert_eq!(msg.wallops_text(), Some("Server restarting soon"));
+        assert_eq!(parse_message("WALLOPS\r\n").unwrap().wallops_text(), None);
+        assert_eq!(parse_message("PRIVMSG #chan :WALLOPS\r\n").unwrap().wallops_text(), None);
+    }
+    #[test]
+    fn test_numeric_args() {
+        let msg = parse_message(":server 001 RustBot :Welcome to the network\r\n").unwrap();
+        assert_eq!(msg.numeric_args().unwrap(), &["Welcome to the network"]);
+        let msg = parse_message(":server 433 * nick :Nickname is already in use\r\n").unwrap();
+        assert_eq!(msg.numeric_args().unwrap(), &["nick", "Nickname is already in use"]);
+        assert!(parse_message(":server 376\r\n").unwrap().numeric_args().unwrap().is_empty());
+        assert!(parse_message(":server 376 RustBot\r\n").unwrap().numeric_args().unwrap().is_empty());
+        assert_eq!(parse_message("PRIVMSG #chan :hi\r\n").unwrap().numeric_args(), None);
+    }
+    #[test]
+    fn test_cloning_borrowed_messages() {
+        let input = String::from("@time=now :nick!user@host PRIVMSG #chan :hi\r\n");
+        let msg = parse_message(&input).unwrap();
+        let copy = msg.clone();
+        assert_eq!(copy.to_owned(), msg.to_owned());
+        assert!(copy.params.iter().all(|p| matches!(*p, Cow::Borrowed(_))));
+        assert!(matches!(copy.raw, Cow::Borrowed(_)));
+    }
+    #[test]
+    fn test_comparing_messages() {
+        let msg = parse_message(":nick!user@host PRIVMSG #chan :hi there\r\n").unwrap();
+        assert_eq!(msg, parse_message(":nick!user@host   PRIVMSG  #chan :hi there\n").unwrap());
+        assert_ne!(msg, parse_message(":nick!user@host PRIVMSG #chan :hi\r\n").unwrap());
+        assert_ne!(msg, parse_message(":nick!user@host PRIVMSG #chan hi\r\n").unwrap());
+        let owned = msg.to_owned();
+        assert_eq!(owned.as_message(), msg);
+        let built = MessageBuilder::new().command("PRIVMSG").param("#chan").trailing("hi there").build().unwrap();
+        assert_eq!(parse_message(&built.to_string()).unwrap(), built.as_message());
+    }
+    #[test]
+    fn test_keeping_tabs_in_params() {
+        let msg = parse_message(":nick!user@host PRIVMSG #chan :col1\tcol2\t col3\r\n").unwrap();
+        assert_eq!(msg.params, vec!["#chan", "col1\tcol2\t col3"]);
+        let msg = parse_message("CMD a\tb c\r\n").unwrap();
+        assert_eq!(msg.params, vec!["a\tb", "c"]);
+    }
+    #[test]
+    fn test_rejecting_tabs_after_tags_and_prefix() {
+        assert_eq!(parse_message("@a=b \tPING\r\n").unwrap_err(), ParserError::MalformedCommand { position: 5 });
+        assert_eq!(parse_message("@a=b\tPING\r\n").unwrap_err(), ParserError::MissingCommand { position: 9 });
+        assert_eq!(parse_message(":irc.example.com \tPING\r\n").unwrap_err(), ParserError::MalformedCommand { position: 17 });
+        assert_eq!(parse_message(":irc.example.com\tPING\r\n").unwrap_err(), ParserError::MissingCommand { position: 21 });
+        assert_eq!(parse_message_lenient("nick!user@host \tPING\r\n").unwrap_err(), ParserError::MalformedCommand { position: 15 });
+    }
+    #[test]
+    fn test_params_after() {
+        let msg = parse_message("CMD a b c\r\n").unwrap();
+        assert_eq!(msg.params_after(0), &["a", "b", "c"]);
+        assert_eq!(msg.params_after(1), &["b", "c"]);
+        assert!(msg.params_after(3).is_empty());
+        assert!(msg.params_after(100).is_empty());
+    }
+    #[test]
+    fn test_params_with_trailing() {
+        let msg = parse_message(":nick!user@host PRIVMSG #chan :hello world\r\n").unwrap();
+        assert_eq!(msg.params_with_trailing(), vec!["#chan", "hello world"]);
+        assert_eq!(msg.params_with_trailing(), msg.params);
+        assert_eq!(parse_message("MODE #chan +o nick\r\n").unwrap().params_with_trailing(), vec!["#chan", "+o", "nick"]);
+        assert_eq!(parse_message("PRIVMSG #chan :\r\n").unwrap().params_with_trailing(), vec!["#chan", ""]);
+        assert!(parse_message("QUIT\r\n").unwrap().params_with_trailing().is_empty());
+    }
+    #[test]
+    fn test_invite_info() {
+        assert_eq!(parse_message(":op!user@host INVITE nick #chan\r\n").unwrap().invite_info(), Some(("nick", "#chan")));
+        assert_eq!(parse_message(":op!user@host INVITE nick :#chan\r\n").unwrap().invite_info(), Some(("nick", "#chan")));
+        assert_eq!(parse_message(":op!user@host INVITE nick\r\n").unwrap().invite_info(), None);
+        assert_eq!(parse_message(":op!user@host KICK nick #chan\r\n").unwrap().invite_info(), None);
+    }
+    #[test]
+    fn test_parse_message_lenient() {
+        let msg = parse_message_lenient("nick!~user@host PRIVMSG #chan :hi\r\n").unwrap();
+        assert_eq!(msg.prefix, Some(Prefix::User { nick: "nick", user: Some("~user"), host: Some("host") }));
+        assert!(msg.command.matches("PRIVMSG"));
+        assert_eq!(msg.params, vec!["#chan", "hi"]);
+        let msg = parse_message_lenient("@time=now nick!user@host JOIN #chan\r\n").unwrap();
+        assert_eq!(msg.prefix.as_ref().and_then(Prefix::nick), Some("nick"));
+        assert_eq!(msg.raw_tag("time"), Some("now"));
+        // Lines that parse strictly parse the same way
+        for line in &["PRIVMSG #chan :hi\r\n", ":nick!user@host PRIVMSG #chan :hi\r\n", ":irc.example.com NOTICE * :hi\r\n"] {
+            assert_eq!(parse_message_lenient(line).unwrap(), parse_message(line).unwrap(), "{}", line);
+        }
+        // Tokens that aren't a full user mask are left as the command, which they aren't valid as
+        for line in &["nick@host PRIVMSG #chan :hi\r\n", "nick!user PRIVMSG #chan :hi\r\n", "irc.example.com NOTICE * :hi\r\n"] {
+            assert_eq!(parse_message_lenient(line).unwrap_err(), ParserError::MalformedCommand { position: 0 }, "{}", line);
+        }
+        assert_eq!(parse_message_lenient("nick!user@host\r\n").unwrap_err(), ParserError::MalformedCommand { position: 0 });
+        assert_eq!(parse_message("nick!user@host PRIVMSG #chan :hi\r\n").unwrap_err(), ParserError::MalformedCommand { position: 0 });
+        assert_eq!(parse_message_lenient(": BROKEN\r\n").unwrap_err(), ParserError::MalformedPrefix { position: 0 });
+    }
+    #[test]
+    fn test_parse_message_line() {
+        let log = ":nick!user@host PRIVMSG #chan :hi there\nPING :server\r\n:server 376 nick :End of MOTD";
+        let msgs: Vec<_> = log.lines().map(|line| parse_message_line(line).unwrap()).collect();
+        assert_eq!(msgs[0].params, vec!["#chan", "hi there"]);
+        assert_eq!(msgs[0].raw, ":nick!user@host PRIVMSG #chan :hi there");
+        assert!(msgs[1].is_ping());
+        assert_eq!(msgs[2].command, Command::Numeric(376));
+        assert_eq!(parse_message_line("PING :server\r\n").unwrap(), parse_message("PING :server\r\n").unwrap());
+        assert_eq!(parse_message_line("QUIT").unwrap().params, Vec::<&str>::new());
+        assert_eq!(parse_message_line(":nick!u@h ").unwrap_err(), ParserError::MissingCommand { position: 10 });
+        assert_eq!(parse_message_line("").unwrap_err(), ParserError::MissingCommand { position: 0 });
+        assert_eq!(parse_message("PING :server").unwrap_err(), ParserError::Incomplete);
+    }
+    #[test]
+    fn test_trailing() {
+        assert_eq!(parse_message(":nick!user@host PRIVMSG #chan :hi there\r\n").unwrap().trailing(), Some("hi there"));
+        assert_eq!(parse_message(":nick!user@host QUIT :\r\n").unwrap().trailing(), Some(""));
+        assert_eq!(parse_message(":nick!user@host PRIVMSG #chan hi\r\n").unwrap().trailing(), None);
+        assert_eq!(parse_message("PING\r\n").unwrap().trailing(), None);
+    }
+    #[test]
+    fn test_parse_dcc() {
+        let msg = parse_message(":nick!user@host PRIVMSG me :\x01DCC SEND file.txt 3232235777 5000 1024\x01\r\n").unwrap();
+        assert_eq!(parse_dcc(&msg.ctcp().unwrap()), Some(Dcc::Send { filename: "file.txt", ip: Ipv4Addr::new(192, 168, 1, 1), port: 5000, size: Some(1024) }));
+        let msg = parse_message(":nick!user@host PRIVMSG me :\x01DCC SEND \"my holiday.jpg\" 2130706433 5000\x01\r\n").unwrap();
+        assert_eq!(parse_dcc(&msg.ctcp().unwrap()), Some(Dcc::Send { filename: "my holiday.jpg", ip: Ipv4Addr::new(127, 0, 0, 1), port: 5000, size: None }));
+        let msg = parse_message(":nick!user@host PRIVMSG me :\x01DCC CHAT chat 2130706433 6000\x01\r\n").unwrap();
+        assert_eq!(parse_dcc(&msg.ctcp().unwrap()), Some(Dcc::Chat { ip: Ipv4Addr::new(127, 0, 0, 1), port: 6000 }));
+        let msg = parse_message(":nick!user@host PRIVMSG me :\x01DCC RESUME \"a b.txt\" 5000 512\x01\r\n").unwrap();
+        assert_eq!(parse_dcc(&msg.ctcp().unwrap()), Some(Dcc::Resume { filename: "a b.txt", port: 5000, position: 512 }));
+        let invalid = ["DCC SEND file.txt 1.2.3.4 5000", "DCC SEND file.txt 3232235777 70000", "DCC SEND \"unterminated 1 2", "DCC GET file.txt 1 2", "PING 123"];
+        for params in &invalid {
+            let (command, params) = params.split_once(' ').unwrap();
+            assert_eq!(parse_dcc(&Ctcp { command, params }), None, "{}", params);
+        }
+    }
+    #[test]
+    fn test_sasl_payload() {
+        assert_eq!(parse_message("AUTHENTICATE +\r\n").unwrap().sasl_payload(), Some(SaslChunk::Empty));
+        assert_eq!(parse_message("AUTHENTICATE *\r\n").unwrap().sasl_payload(), Some(SaslChunk::Abort));
+        let msg = parse_message("AUTHENTICATE AGpqAGpq\r\n").unwrap();
+        assert_eq!(msg.sasl_payload(), Some(SaslChunk::Data { base64: "AGpqAGpq", more: false }));
+        let line = format!("AUTHENTICATE {}\r\n", "A".repeat(SASL_CHUNK_LENGTH));
+        let msg = parse_message(&line).unwrap();
+        assert!(matches!(msg.sasl_payload(), Some(SaslChunk::Data { more: true, .. })));
+        assert_eq!(parse_message("AUTHENTICATE\r\n").unwrap().sasl_payload(), None);
+        assert_eq!(parse_message("PRIVMSG #chan :+\r\n").unwrap().sasl_payload(), None);
+    }
+    #[test]
+    fn test_channels() {
+        assert_eq!(parse_message("JOIN #a,&b,c key\r\n").unwrap().channels(), vec!["#a", "&b"]);
+        assert_eq!(parse_message(":nick!user@host JOIN :#chan\r\n").unwrap().channels(), vec!["#chan"]);
+        assert_eq!(parse_message(":op!user@host KICK #chan nick :#off topic\r\n").unwrap().channels(), vec!["#chan"]);
+        assert_eq!(parse_message(":server 332 me #chan :#1 topic\r\n").unwrap().channels(), vec!["#chan"]);
+        assert!(parse_message("PRIVMSG nick :hi\r\n").unwrap().channels().is_empty());
+        assert!(parse_message(":nick!user@host PRIVMSG nick :#rust\r\n").unwrap().channels().is_empty());
+        assert_eq!(parse_message(":op!user@host KICK #chan nick :#off\r\n").unwrap().channels(), vec!["#chan"]);
+        assert_eq!(parse_message(":op!user@host INVITE nick :#chan\r\n").unwrap().channels(), vec!["#chan"]);
+        assert_eq!(parse_message(":nick!user@host PART :#chan\r\n").unwrap().channels(), vec!["#chan"]);
+        assert_eq!(parse_message(":nick!user@host PART #chan :#elsewhere\r\n").unwrap().channels(), vec!["#chan"]);
+        let msg = parse_message("MODE #a +o nick\r\n").unwrap();
+        assert!(msg.channels_with_types("&").is_empty());
+        assert_eq!(msg.channels_with_types("#"), vec!["#a"]);
+        let mut msg = Message::new("PRIVMSG").unwrap();
+        msg.has_trailing = true;
+        assert!(msg.channels().is_empty());
+    }
+    #[test]
+    fn test_displaying_numerics() {
+        assert_eq!(Command::Numeric(4).to_string(), "004");
+        assert_eq!(Command::Numeric(433).to_string(), "433");
+        let line = ":server 001 RustBot :Welcome\r\n";
+        assert_eq!(parse_message(line).unwrap().to_string(), line);
+    }
+    #[test]
+    fn test_classifying_numerics() {
+        let classify = |n| (Command::Numeric(n).is_reply(), Command::Numeric(n).is_error());
+        assert_eq!(classify(0), (false, false));
+        assert_eq!(classify(1), (true, false));
+        assert_eq!(classify(399), (true, false));
+        assert_eq!(classify(400), (false, true));
+        assert_eq!(classify(599), (false, true));
+        assert_eq!(classify(600), (false, false));
+        assert!(!Command::Named("ERROR".into()).is_error());
+        assert!(parse_message(":server 433 * nick :In use\r\n").unwrap().is_error_numeric());
+        assert!(!parse_message(":server 001 nick :Welcome\r\n").unwrap().is_error_numeric());
+    }
+    #[test]
+    fn test_prefix_parse_is_atomic() {
+        let input = &b"PRIVMSG #chan :hi\r\n"[..];
+        assert_eq!(super::optional_prefix(input), Ok((input, None)));
+        for input in &[&b"::nick PRIVMSG #chan :hi\r\n"[..], &b":!user@host PRIVMSG #chan :hi\r\n"[..], &b": PRIVMSG\r\n"[..]] {
+            match super::optional_prefix(input) {
+                Err(nom::Err::Error(failure)) => {
+                    assert_eq!(failure.input, *input);
+                    assert_eq!(failure.kind, FailureKind::MalformedPrefix);
+                },
+                other => panic!("Unexpected result {:?}", other)
+            }
+            assert_eq!(parse_message_bytes(input).unwrap_err(), ParserError::MalformedPrefix { position: 0 });
+        }
+        let msg = parse_message(":nick PRIVMSG #chan ::-)\r\n").unwrap();
+        assert_eq!(msg.prefix, Some(Prefix::User { nick: "nick", user: None, host: None }));
+        assert_eq!(msg.params, vec!["#chan", ":-)"]);
+    }
+    #[test]
+    fn test_strip_formatting() {
+        assert!(matches!(strip_formatting("plain text"), Cow::Borrowed("plain text")));
+        assert_eq!(strip_formatting("\x02bold\x02 and \x1funderlined\x1f\x0f"), "bold and underlined");
+        assert_eq!(strip_formatting("\x034red\x03 \x0304,12on blue\x03,x \x03100"), "red on blue,x 0");
+        assert_eq!(strip_formatting("\x02\x0312,4\x1d\x16nested\x16\x1d\x03\x02 done"), "nested done");
+        assert_eq!(strip_formatting("\x03,5comma"), ",5comma");
+        assert_eq!(strip_formatting("\x04FF0000,00ff00hex\x04 \x04abc"), "hex abc");
+        assert_eq!(strip_formatting("k\x02ä\x02ä\x03"), "kää");
+    }
+    #[test]
+    fn test_parse_channel_mode_reply() {
+        let msg = parse_message(":server 324 me #chan +ntkl secret 50\r\n").unwrap();
+        assert_eq!(parse_channel_mode_reply(&msg), Some(("#chan", vec![
+            ModeChange { add: true, mode: 'n', arg: None },
+            ModeChange { add: true, mode: 't', arg: None },
+            ModeChange { add: true, mode: 'k', arg: Some("secret") },
+            ModeChange { add: true, mode: 'l', arg: Some("50") }
+        ])));
+        let msg = parse_message(":server 324 me #chan +fn :[5:5]\r\n").unwrap();
+        assert_eq!(parse_channel_mode_reply_with_arg_modes(&msg, "fkl").unwrap().1[0], ModeChange { add: true, mode: 'f', arg: Some("[5:5]") });
+        assert_eq!(parse_channel_mode_reply(&parse_message(":server 324 me #chan\r\n").unwrap()), None);
+        assert_eq!(parse_channel_mode_reply(&parse_message("MODE #chan +k key\r\n").unwrap()), None);
+    }
+    #[test]
+    fn test_message_constructors() {
+        assert_eq!(Message::privmsg("#chan", "hello world").unwrap().to_string(), "PRIVMSG #chan :hello world\r\n");
+        assert_eq!(Message::privmsg("nick", "hi").unwrap().to_string(), "PRIVMSG nick :hi\r\n");
+        assert_eq!(Message::notice("nick", "hi there").unwrap().to_string(), "NOTICE nick :hi there\r\n");
+        assert_eq!(Message::join("#chan").unwrap().to_string(), "JOIN #chan\r\n");
+        assert_eq!(Message::nick("RustBot").unwrap().to_string(), "NICK RustBot\r\n");
+        assert_eq!(Message::user("rbot", "Rust Bot").unwrap().to_string(), "USER rbot 0 * :Rust Bot\r\n");
+        assert_eq!(Message::new("QUIT").unwrap().to_string(), "QUIT\r\n");
+        let msg = Message::privmsg("#chan", "hi there").unwrap();
+        assert_eq!(parse_message(&msg.to_string()).unwrap(), msg);
+        assert_eq!(Message::privmsg("#c", "hi\r\nQUIT"), Err(ParserError::InvalidParam { index: 1 }));
+        assert_eq!(Message::privmsg("#a\r\nQUIT", "hi"), Err(ParserError::InvalidParam { index: 0 }));
+        assert_eq!(Message::privmsg("#a #b", "hi"), Err(ParserError::InvalidParam { index: 0 }));
+        assert_eq!(Message::notice("nick", "a\0b"), Err(ParserError::InvalidParam { index: 1 }));
+        assert_eq!(Message::join("#chan\n"), Err(ParserError::InvalidParam { index: 0 }));
+        assert_eq!(Message::nick("Rust\rBot"), Err(ParserError::InvalidParam { index: 0 }));
+        assert_eq!(Message::user("rbot", "Rust\nBot"), Err(ParserError::InvalidParam { index: 3 }));
+        assert_eq!(Message::new("QUIT\r\nJOIN"), Err(ParserError::MalformedCommand { position: 0 }));
+        assert_eq!(Message::new(""), Err(ParserError::MalformedCommand { position: 0 }));
+    }
+    #[test]
+    fn test_ipv6_host_in_prefix() {
+        let line = ":nick!user@2001:db8::1 PRIVMSG #chan :hi :)\r\n";
+        let msg = parse_message(line).unwrap();
+        assert_eq!(msg.prefix, Some(Prefix::User { nick: "nick", user: Some("user"), host: Some("2001:db8::1") }));
+        assert_eq!(msg.params, vec!["#chan", "hi :)"]);
+        assert_eq!(msg.to_string(), line);
+        let msg = parse_message_lenient("nick!~user@::ffff:127.0.0.1 JOIN #chan\r\n").unwrap();
+        assert_eq!(msg.prefix.as_ref().and_then(Prefix::host), Some("::ffff:127.0.0.1"));
+        assert_eq!(msg.params, vec!["#chan"]);
     }
 }